@@ -0,0 +1,142 @@
+use std::{
+    ffi::CString,
+    fs::{File, rename, set_permissions, symlink_metadata},
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use crate::{Action, Context, Error, Op, Result, dry, mkparent, rmf, tmp_sibling};
+
+/// # Writes a file atomically.
+/// Creates missing parents, writes `contents` to a temporary sibling, syncs it, and renames it over
+/// `path`. Readers see either the old contents or the new contents, never a mix. When replacing a
+/// file, its permissions are kept. A symlink at `path` is not followed: it is replaced by the new
+/// file, and its target is left alone.
+pub fn write_atomic<P, C>(path: P, contents: C) -> Result<()>
+where
+    P: AsRef<Path>,
+    C: AsRef<[u8]>,
+{
+    let path = path.as_ref();
+    mkparent(path)?;
+    if dry::active() {
+        if symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+            return Err(Error::new(
+                Op::WriteAtomic,
+                path,
+                io::ErrorKind::IsADirectory.into(),
+            ));
+        }
+        dry::record(Action::Write(path.to_path_buf()));
+        return Ok(());
+    }
+
+    let (tmp, f) = tmp_sibling(path, |tmp| File::create_new(tmp)).ctx(Op::WriteAtomic, path)?;
+    stage(path, &tmp, f, contents.as_ref())
+        .inspect_err(|_| {
+            let _ = rmf(&tmp);
+        })
+        .and_then(|()| sync_parent(path))
+        .ctx(Op::WriteAtomic, path)
+}
+
+fn stage(path: &Path, tmp: &Path, mut f: File, contents: &[u8]) -> io::Result<()> {
+    f.write_all(contents)?;
+    if let Ok(meta) = symlink_metadata(path)
+        && meta.is_file()
+    {
+        set_permissions(tmp, meta.permissions())?;
+    }
+    f.sync_all()?;
+    rename(tmp, path)
+}
+
+/// Syncs the directory containing `path` so a rename into it survives a crash
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+/// Renames `from` to `to`, failing with `AlreadyExists` instead of replacing anything
+pub(crate) fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let cstr = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (from_c, to_c) = (cstr(from)?, cstr(to)?);
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_c.as_ptr(),
+            libc::AT_FDCWD,
+            to_c.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    // NOTE: Some filesystems don't support RENAME_NOREPLACE, so check first there instead
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EINVAL) {
+        return Err(e);
+    }
+    if symlink_metadata(to).is_ok() {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    rename(from, to)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{Permissions, metadata, read_to_string},
+        os::unix::fs::{PermissionsExt, symlink},
+    };
+
+    use super::*;
+    use crate::tempdir;
+
+    #[test]
+    fn write_atomic_replaces_and_keeps_mode() {
+        let t = tempdir().unwrap();
+        let f = &t.path().join("replace/file");
+        assert!(write_atomic(f, "old").is_ok());
+        set_permissions(f, Permissions::from_mode(0o600)).unwrap();
+
+        assert!(write_atomic(f, "new").is_ok());
+        assert_eq!(read_to_string(f).unwrap(), "new");
+        assert_eq!(metadata(f).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn write_atomic_replaces_symlink() {
+        let t = tempdir().unwrap();
+        let (target, link) = (t.path().join("target"), t.path().join("link"));
+        write_atomic(&target, "old").unwrap();
+        set_permissions(&target, Permissions::from_mode(0o600)).unwrap();
+        symlink(&target, &link).unwrap();
+
+        write_atomic(&link, "new").unwrap();
+        assert!(symlink_metadata(&link).unwrap().is_file());
+        assert_eq!(read_to_string(&link).unwrap(), "new");
+        assert_eq!(read_to_string(&target).unwrap(), "old");
+        assert_ne!(metadata(&link).unwrap().permissions().mode() & 0o777, 0o777);
+    }
+
+    #[test]
+    fn rename_noreplace_keeps_target() {
+        let t = tempdir().unwrap();
+        let (a, b) = (t.path().join("a"), t.path().join("b"));
+        write_atomic(&a, "a").unwrap();
+        write_atomic(&b, "b").unwrap();
+        let e = rename_noreplace(&a, &b).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(&b).unwrap(), "b");
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use crate::{CreateOutcome, MkdirOptions, MkfOptions, Result, dry, rmr};
+
+/// # Removes the paths it created when dropped.
+/// Only paths its helpers actually created are tracked, including created parents; anything that
+/// already existed is left alone. On drop, tracked paths are removed recursively, newest first,
+/// unless `keep()` or `commit()` was called. Failures are logged, not raised.
+///
+/// During a `DryRun`, creation is only pretended, so nothing is tracked.
+#[derive(Debug, Default)]
+#[must_use = "created paths are removed when this is dropped"]
+pub struct Cleanup {
+    created: Vec<PathBuf>,
+}
+
+impl Cleanup {
+    /// # Starts tracking nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # Creates a directory, tracking it if it was created.
+    /// See `mkdir()`.
+    pub fn mkdir<P>(&mut self, dir: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let outcome = MkdirOptions::new().mkdir(dir)?;
+        Ok(self.track(dir, outcome))
+    }
+
+    /// # Creates a directory and all its parents, tracking those that were created.
+    /// See `mkdir_p()`.
+    pub fn mkdir_p<P>(&mut self, dir: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        self.mkparent(dir)?;
+        let outcome = MkdirOptions::new().parents(true).mkdir(dir)?;
+        Ok(self.track(dir, outcome))
+    }
+
+    /// # Creates a file, tracking it if it was created.
+    /// See `mkf()`.
+    pub fn mkf<P>(&mut self, file: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        let outcome = MkfOptions::new().mkf(file)?;
+        Ok(self.track(file, outcome))
+    }
+
+    /// # Creates a file with parents, tracking those that were created.
+    /// See `mkf_p()`.
+    pub fn mkf_p<P>(&mut self, file: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        self.mkparent(file)?;
+        let outcome = MkfOptions::new().parents(true).mkf(file)?;
+        Ok(self.track(file, outcome))
+    }
+
+    /// # Stops tracking, leaving everything in place.
+    pub fn keep(mut self) {
+        self.created.clear();
+    }
+
+    /// # Stops tracking, leaving everything in place.
+    /// The same as `keep()`.
+    pub fn commit(self) {
+        self.keep()
+    }
+
+    /// Creates the missing parents of `path`, tracking those that were created
+    fn mkparent(&mut self, path: &Path) -> Result<()> {
+        let mut created = Vec::new();
+        let result = MkdirOptions::new().mkparent_into(path, &mut created);
+        if !dry::active() {
+            self.created.extend(created);
+        }
+        result
+    }
+
+    fn track(&mut self, path: &Path, outcome: CreateOutcome) -> CreateOutcome {
+        if outcome.created() && !dry::active() {
+            self.created.push(path.to_path_buf());
+        }
+        outcome
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        while let Some(path) = self.created.pop() {
+            if let Err(e) = dry::bypass(|| rmr(&path)) {
+                tracing::warn!("Failed to clean up {path:?}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{mkdir_p, tempdir};
+
+    #[test]
+    fn remove_only_created_paths() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkdir_p(d.join("existing")).unwrap();
+
+        {
+            let mut c = Cleanup::new();
+            c.mkf_p(d.join("existing/a/b/file")).unwrap();
+            c.mkdir_p(d.join("existing")).unwrap();
+            std::fs::write(d.join("existing/a/extra"), "").unwrap();
+        }
+        assert!(d.join("existing").exists() && !d.join("existing/a").exists());
+
+        let mut c = Cleanup::new();
+        c.mkdir(d.join("kept")).unwrap();
+        c.keep();
+        assert!(d.join("kept").exists());
+    }
+}
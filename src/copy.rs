@@ -0,0 +1,510 @@
+use std::{
+    ffi::CString,
+    fs::{self, File, Metadata, Permissions, copy, rename},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt, symlink},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Action, Context, Error, Mounts, Op, Result, dry, mkdir, mkparent,
+    mounts::{Boundary, crossing},
+    rmf, rmr, tmp_sibling, vacant,
+};
+
+/// # Options for copying.
+/// The defaults roughly correspond to `cp -a`: mode, timestamps and symlinks are preserved.
+#[derive(Clone, Copy, Debug)]
+pub struct CopyOptions {
+    mode: bool,
+    timestamps: bool,
+    dereference: bool,
+    mounts: Mounts,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CopyOptions {
+    /// # Creates the default copy options.
+    pub const fn new() -> Self {
+        Self {
+            mode: true,
+            timestamps: true,
+            dereference: false,
+            mounts: Mounts::Cross,
+        }
+    }
+
+    /// # Whether to preserve permission bits.
+    /// If disabled, new files and directories get the default mode filtered by umask.
+    pub const fn preserve_mode(mut self, yes: bool) -> Self {
+        self.mode = yes;
+        self
+    }
+
+    /// # Whether to preserve access and modification times.
+    /// Timestamps are not preserved for symlinks themselves.
+    pub const fn preserve_timestamps(mut self, yes: bool) -> Self {
+        self.timestamps = yes;
+        self
+    }
+
+    /// # Whether to copy what symlinks point to instead of the symlinks themselves.
+    pub const fn dereference(mut self, yes: bool) -> Self {
+        self.dereference = yes;
+        self
+    }
+
+    /// # What `cp_r()` does at mount points.
+    /// With `Mounts::Skip`, mount points are copied as empty directories, like `cp -x`.
+    /// `Mounts::Refuse` checks for mount points before copying anything.
+    pub const fn mounts(mut self, mounts: Mounts) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    /// # Copies a file or symlink with these options.
+    /// See `cp()`.
+    pub fn cp<P, Q>(&self, src: P, dst: Q) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        let meta = self.stat(src).ctx2(Op::Cp, src, dst)?;
+        if meta.is_dir() {
+            return Err(Error::with_dst(
+                Op::Cp,
+                src,
+                dst,
+                io::ErrorKind::IsADirectory.into(),
+            ));
+        }
+
+        refuse_same(Op::Cp, src, dst)?;
+        mkparent(dst)?;
+        if dry::active() {
+            dry::record(Action::Copy(src.to_path_buf(), dst.to_path_buf()));
+            return Ok(());
+        }
+        self.copy_entry(Op::Cp, src, dst, &meta)
+    }
+
+    /// # Copies a path recursively with these options.
+    /// See `cp_r()`.
+    pub fn cp_r<P, Q>(&self, src: P, dst: Q) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        let boundary = match self.mounts {
+            Mounts::Cross => None,
+            _ => Some(Boundary::new(src)?),
+        };
+        if self.mounts == Mounts::Refuse && boundary.as_ref().is_some_and(|b| !b.is_clear()) {
+            return Err(Error::with_dst(Op::CpR, src, dst, crossing()));
+        }
+        if self.stat(src).is_ok_and(|m| m.is_dir()) && within(src, dst).ctx2(Op::CpR, src, dst)? {
+            return Err(Error::with_dst(
+                Op::CpR,
+                src,
+                dst,
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot copy a directory into itself",
+                ),
+            ));
+        }
+
+        mkparent(dst)?;
+        if dry::active() {
+            self.stat(src).ctx2(Op::CpR, src, dst)?;
+            dry::record(Action::Copy(src.to_path_buf(), dst.to_path_buf()));
+            return Ok(());
+        }
+        self.copy_tree(src, dst, boundary.as_ref(), &mut Vec::new())
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        if self.dereference {
+            fs::metadata(path)
+        } else {
+            fs::symlink_metadata(path)
+        }
+    }
+
+    /// Copies `src` to `dst`, with `ancestors` holding the (dev, ino) of the directories above `src`
+    fn copy_tree(
+        &self,
+        src: &Path,
+        dst: &Path,
+        boundary: Option<&Boundary>,
+        ancestors: &mut Vec<(u64, u64)>,
+    ) -> Result<()> {
+        let meta = self.stat(src).ctx2(Op::CpR, src, dst)?;
+        if !meta.is_dir() {
+            refuse_same(Op::CpR, src, dst)?;
+            return self.copy_entry(Op::CpR, src, dst, &meta);
+        }
+
+        // NOTE: Only a dereferenced symlink can lead back to an ancestor
+        let id = (meta.dev(), meta.ino());
+        if ancestors.contains(&id) {
+            return Err(Error::with_dst(
+                Op::CpR,
+                src,
+                dst,
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "symlink loops back to a directory being copied",
+                ),
+            ));
+        }
+
+        mkdir(dst)?;
+        if boundary.is_some_and(|b| b.excludes(meta.dev(), meta.ino())) {
+            if self.mounts == Mounts::Refuse {
+                return Err(Error::with_dst(Op::CpR, src, dst, crossing()));
+            }
+            tracing::debug!("Skipping the contents of mount point {src:?}");
+        } else {
+            ancestors.push(id);
+            for entry in fs::read_dir(src).ctx2(Op::CpR, src, dst)? {
+                let entry = entry.ctx2(Op::CpR, src, dst)?;
+                self.copy_tree(
+                    &entry.path(),
+                    &dst.join(entry.file_name()),
+                    boundary,
+                    ancestors,
+                )?;
+            }
+            ancestors.pop();
+        }
+
+        // NOTE: Metadata is applied after the contents so read-only directories can be populated
+        // and so populating doesn't clobber the mtime
+        self.apply_metadata(dst, &meta).ctx2(Op::CpR, src, dst)
+    }
+
+    fn copy_entry(&self, op: Op, src: &Path, dst: &Path, meta: &Metadata) -> Result<()> {
+        let ft = meta.file_type();
+        if ft.is_symlink() {
+            rmf(dst)?;
+            return fs::read_link(src)
+                .and_then(|target| symlink(target, dst))
+                .ctx2(op, src, dst);
+        }
+
+        if !ft.is_file() {
+            return Err(Error::with_dst(
+                op,
+                src,
+                dst,
+                io::ErrorKind::Unsupported.into(),
+            ));
+        }
+
+        if self.mode {
+            copy(src, dst).map(drop)
+        } else {
+            File::open(src)
+                .and_then(|mut f| io::copy(&mut f, &mut File::create(dst)?))
+                .map(drop)
+        }
+        .and_then(|()| self.apply_metadata(dst, meta))
+        .ctx2(op, src, dst)
+    }
+
+    fn apply_metadata(&self, dst: &Path, meta: &Metadata) -> io::Result<()> {
+        if self.timestamps {
+            set_times(dst, meta)?;
+        }
+
+        if self.mode {
+            fs::set_permissions(dst, Permissions::from_mode(meta.permissions().mode()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Errors if `dst` is `src`, a hard link to it, or a symlink to it
+///
+/// Copying would truncate `dst` before reading `src`, losing its contents.
+fn refuse_same(op: Op, src: &Path, dst: &Path) -> Result<()> {
+    let id = |m: Metadata| (m.dev(), m.ino());
+    let dst_id = match fs::metadata(dst) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        r => r.map(id).ctx2(op, src, dst)?,
+    };
+    if fs::metadata(src).map(id).ctx2(op, src, dst)? != dst_id {
+        return Ok(());
+    }
+    Err(Error::with_dst(
+        op,
+        src,
+        dst,
+        io::Error::new(io::ErrorKind::InvalidInput, "are the same file"),
+    ))
+}
+
+/// Sets the access and modification times of `path` to those in `meta`
+///
+/// Works by path, so `path` doesn't need to be readable or writable.
+fn set_times(path: &Path, meta: &Metadata) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime(),
+            tv_nsec: meta.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec(),
+        },
+    ];
+    let ret = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Whether `dst` is `src` or under it, once both are resolved
+///
+/// `dst` may not exist yet, so its nearest existing ancestor is resolved and the rest is appended.
+fn within(src: &Path, dst: &Path) -> io::Result<bool> {
+    let src = fs::canonicalize(src)?;
+    let dst = std::path::absolute(dst)?;
+    let mut rest = Vec::new();
+    for p in dst.ancestors() {
+        match fs::canonicalize(p) {
+            Ok(resolved) => {
+                let dst = rest.iter().rev().fold(resolved, |d: PathBuf, c| d.join(c));
+                return Ok(dst.starts_with(&src));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                rest.extend(p.file_name());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// # Copies a file or symlink.
+/// Creates missing parents of `dst` and overwrites existing files. Refuses to copy directories.
+/// Preserves mode, timestamps and symlinks; see `CopyOptions` to change that.
+pub fn cp<P, Q>(src: P, dst: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    CopyOptions::new().cp(src, dst)
+}
+
+/// # Copies a path recursively.
+/// `dst` is the path of the copy, not a directory to copy into. Creates missing parents of `dst`
+/// and merges into existing directories. Roughly corresponds to `cp -a`.
+pub fn cp_r<P, Q>(src: P, dst: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    CopyOptions::new().cp_r(src, dst)
+}
+
+/// # Moves a path.
+/// Renames when possible, falling back to a metadata-preserving recursive copy followed by
+/// `rmr(src)` when `src` and `dst` are on different filesystems. Creates missing parents of `dst`.
+///
+/// The fallback refuses the same things `rename` does: a directory only replaces an empty
+/// directory, and a file never replaces a directory. It copies to a temporary sibling of `dst` and
+/// renames that into place, so if the copy fails, only the partial copy is removed and `dst` is
+/// left as it was.
+pub fn mv<P, Q>(src: P, dst: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    mkparent(dst)?;
+    if dry::active() {
+        in_the_way(src, dst).ctx2(Op::Mv, src, dst)?;
+        dry::record(Action::Move(src.to_path_buf(), dst.to_path_buf()));
+        return Ok(());
+    }
+
+    match rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            tracing::debug!("Falling back to copying {src:?} to {dst:?} across devices");
+        }
+        r => return r.ctx2(Op::Mv, src, dst),
+    }
+
+    in_the_way(src, dst).ctx2(Op::Mv, src, dst)?;
+    let (tmp, ()) = tmp_sibling(dst, vacant).ctx2(Op::Mv, src, dst)?;
+    let copied = cp_r(src, &tmp).and_then(|()| rename(&tmp, dst).ctx2(Op::Mv, src, dst));
+    if let Err(e) = copied {
+        if let Err(e) = rmr(&tmp) {
+            tracing::warn!("Failed to clean up partial copy {tmp:?}: {e}");
+        }
+        return Err(e);
+    }
+
+    rmr(src).map(drop)
+}
+
+/// Errors like `rename` would if `src` can't replace `dst`
+///
+/// Only a file or an empty directory can be in the way.
+fn in_the_way(src: &Path, dst: &Path) -> io::Result<()> {
+    let src_dir = fs::symlink_metadata(src)?.is_dir();
+    let dst_meta = match fs::symlink_metadata(dst) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        r => r?,
+    };
+    let e = match (src_dir, dst_meta.is_dir()) {
+        (true, true) if fs::read_dir(dst)?.next().is_some() => io::ErrorKind::DirectoryNotEmpty,
+        (true, false) => io::ErrorKind::NotADirectory,
+        (false, true) => io::ErrorKind::IsADirectory,
+        _ => return Ok(()),
+    };
+    Err(e.into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+    use crate::{mkdir_p, mkf_p, tempdir, tempdir_in};
+
+    #[test]
+    fn cp_r_preserves_tree() {
+        let t = tempdir().unwrap();
+        let root = &t.path().join("tree");
+        let (src, dst) = (root.join("src"), root.join("nested/dst"));
+        mkf_p(src.join("a/b/file")).unwrap();
+        fs::set_permissions(src.join("a/b/file"), Permissions::from_mode(0o640)).unwrap();
+        symlink("a/b/file", src.join("link")).unwrap();
+
+        assert!(cp_r(&src, &dst).is_ok());
+        assert_eq!(
+            fs::metadata(dst.join("a/b/file"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o640
+        );
+        assert_eq!(
+            fs::read_link(dst.join("link")).unwrap(),
+            Path::new("a/b/file")
+        );
+
+        // Copying again merges into the existing tree
+        assert!(cp_r(&src, &dst).is_ok());
+    }
+
+    #[test]
+    fn cp_refuses_the_same_file() {
+        let t = tempdir().unwrap();
+        let f = &t.path().join("same");
+        fs::write(f, "data").unwrap();
+        fs::hard_link(f, t.path().join("hard")).unwrap();
+        symlink("same", t.path().join("soft")).unwrap();
+
+        for dst in [f.clone(), t.path().join("hard"), t.path().join("soft")] {
+            let e = cp(f, &dst).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert!(cp_r(f, &dst).is_err());
+        }
+        assert_eq!(fs::read_to_string(f).unwrap(), "data");
+    }
+
+    #[test]
+    fn cp_r_refuses_itself_and_loops() {
+        let t = tempdir().unwrap();
+        let src = &t.path().join("tree");
+        mkf_p(src.join("sub/file")).unwrap();
+
+        let e = cp_r(src, src.join("sub/copy")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(!src.join("sub/copy").exists());
+        assert!(cp_r(src, src).is_err());
+
+        symlink("..", src.join("sub/up")).unwrap();
+        let deref = CopyOptions::new().dereference(true);
+        assert!(deref.cp_r(src, t.path().join("copy")).is_err());
+        assert!(cp_r(src, t.path().join("copy")).is_ok());
+    }
+
+    #[test]
+    fn cp_refuses_directories() {
+        let t = tempdir().unwrap();
+        let root = &t.path().join("dir");
+        mkdir_p(root).unwrap();
+        assert!(cp(root, root.with_file_name("dir2")).is_err());
+    }
+
+    #[test]
+    fn mv_renames_with_parents() {
+        let t = tempdir().unwrap();
+        let root = &t.path().join("mv");
+        let (src, dst) = (root.join("src"), root.join("a/b/dst"));
+        mkf_p(src.join("file")).unwrap();
+
+        assert!(mv(&src, &dst).is_ok());
+        assert!(!src.exists() && dst.join("file").exists());
+    }
+
+    #[test]
+    fn mv_copies_across_devices() {
+        let (t, shm) = (tempdir().unwrap(), tempdir_in("/dev/shm"));
+        let Ok(shm) = shm else { return };
+        let dev = |p: &Path| fs::metadata(p).unwrap().dev();
+        if dev(t.path()) == dev(shm.path()) {
+            return;
+        }
+        let src = &t.path().join("src");
+        mkf_p(src.join("a/file")).unwrap();
+
+        mkf_p(shm.path().join("full/other")).unwrap();
+        let e = mv(src, shm.path().join("full")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::DirectoryNotEmpty);
+
+        mkdir_p(shm.path().join("empty")).unwrap();
+        mv(src, shm.path().join("empty")).unwrap();
+        assert!(!src.exists() && shm.path().join("empty/a/file").exists());
+
+        // NOTE: Sockets can't be copied, so the copy fails partway
+        mkf_p(src.join("a/file")).unwrap();
+        let _listener = UnixListener::bind(src.join("sock")).unwrap();
+        assert!(mv(src, shm.path().join("partial")).is_err());
+        assert!(src.join("a/file").exists() && !shm.path().join("partial").exists());
+
+        // NOTE: Whatever is in the way survives a failed copy
+        mkdir_p(shm.path().join("kept")).unwrap();
+        assert!(mv(src, shm.path().join("kept")).is_err());
+        fs::write(shm.path().join("old"), "old").unwrap();
+        assert!(mv(src.join("sock"), shm.path().join("old")).is_err());
+        assert_eq!(fs::read_to_string(shm.path().join("old")).unwrap(), "old");
+        let mut names = fs::read_dir(shm.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["empty", "full", "kept", "old"]);
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    cell::RefCell,
+    fs::{read_dir, symlink_metadata},
+    io,
+    marker::PhantomData,
+    mem,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Context, CreateOutcome, Error, Mounts, Op, RemoveOutcome, Result, kind,
+    mounts::{Boundary, crossing},
+};
+
+/// # Something a helper would have done.
+/// Recorded by `DryRun`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    CreateDir(PathBuf),
+    CreateFile(PathBuf),
+    RemoveDir(PathBuf),
+    /// Removing a file, symlink, or special file
+    RemoveFile(PathBuf),
+    /// Copying the first path to the second, recursively if it's a directory
+    Copy(PathBuf, PathBuf),
+    /// Moving the first path to the second
+    Move(PathBuf, PathBuf),
+    /// Creating a symlink at the first path pointing to the second, replacing what's there if asked
+    Symlink(PathBuf, PathBuf),
+    /// Replacing a file's contents atomically, creating it if needed
+    Write(PathBuf),
+}
+
+thread_local! {
+    static PLANS: RefCell<Vec<Vec<Action>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// # Makes helpers on this thread only pretend to change anything.
+/// While a `DryRun` is alive, every helper that changes the filesystem changes nothing, but logs
+/// and records what it would have done and returns the matching outcome. That covers creation,
+/// removal, copying, moving, linking, and writing, as well as `Transaction`, `Cleanup`,
+/// `StagedFile`, and `recover()`. Only `tempdir()` and `tempfile()` still create their private
+/// temporaries, which are removed on drop since persisting them is pretended too. Cleaning up what
+/// was really created, when dropping a temporary, a `Cleanup`, or a `Transaction`, is never
+/// pretended.
+#[must_use = "dry running stops when this is dropped"]
+pub struct DryRun(usize, PhantomData<*const ()>);
+
+impl DryRun {
+    /// # Starts a dry run on the current thread.
+    /// Nested dry runs record separately. Dropping a dry run also ends any started after it.
+    pub fn start() -> Self {
+        let depth = PLANS.with_borrow_mut(|p| {
+            p.push(Vec::new());
+            p.len() - 1
+        });
+        Self(depth, PhantomData)
+    }
+
+    /// # What would have been done so far, in order.
+    pub fn actions(&self) -> Vec<Action> {
+        PLANS.with_borrow(|p| p.get(self.0).cloned().unwrap_or_default())
+    }
+}
+
+impl Drop for DryRun {
+    fn drop(&mut self) {
+        PLANS.with_borrow_mut(|p| p.truncate(self.0));
+    }
+}
+
+/// Whether a dry run is underway on this thread
+pub(crate) fn active() -> bool {
+    PLANS.with_borrow(|p| !p.is_empty())
+}
+
+/// Runs `f` with dry running suspended, so cleanup removes what was really created
+pub(crate) fn bypass<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    struct Resume(Vec<Vec<Action>>);
+    impl Drop for Resume {
+        fn drop(&mut self) {
+            PLANS.with_borrow_mut(|p| *p = mem::take(&mut self.0));
+        }
+    }
+
+    let _resume = Resume(PLANS.with_borrow_mut(mem::take));
+    f()
+}
+
+/// Logs and records something the current dry run would have done
+pub(crate) fn record(action: Action) {
+    match &action {
+        Action::CreateDir(p) => tracing::info!("Would create directory {p:?}"),
+        Action::CreateFile(p) => tracing::info!("Would create file {p:?}"),
+        Action::RemoveDir(p) => tracing::info!("Would remove directory {p:?}"),
+        Action::RemoveFile(p) => tracing::info!("Would remove {p:?}"),
+        Action::Copy(src, dst) => tracing::info!("Would copy {src:?} to {dst:?}"),
+        Action::Move(src, dst) => tracing::info!("Would move {src:?} to {dst:?}"),
+        Action::Symlink(link, target) => tracing::info!("Would link {link:?} to {target:?}"),
+        Action::Write(p) => tracing::info!("Would write {p:?}"),
+    }
+    PLANS.with_borrow_mut(|p| p.last_mut().map(|p| p.push(action)));
+}
+
+/// Whether the current dry run would have created the directory `dir`
+fn planned(dir: &Path) -> bool {
+    PLANS.with_borrow(|p| {
+        p.last()
+            .is_some_and(|p| p.iter().any(|a| *a == Action::CreateDir(dir.to_path_buf())))
+    })
+}
+
+/// Pretends to create `path`, which can only have a missing parent if `parents` is set
+pub(crate) fn create(op: Op, path: &Path, dir: bool, parents: bool) -> Result<CreateOutcome> {
+    if kind(path)
+        .map_err(|e| Error::new(op, path, e.into_io()))?
+        .is_some()
+    {
+        return Ok(CreateOutcome::AlreadyExisted);
+    }
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        let e = match kind(parent).map_err(|e| Error::new(op, path, e.into_io()))? {
+            Some(k) if k.is_dir() => None,
+            // NOTE: Parents are pretended too, so they are still missing
+            None if parents || planned(parent) => None,
+            None => Some(io::ErrorKind::NotFound),
+            Some(_) => Some(io::ErrorKind::NotADirectory),
+        };
+        if let Some(e) = e {
+            return Err(Error::new(op, path, e.into()));
+        }
+    }
+
+    let path = path.to_path_buf();
+    record(if dir {
+        Action::CreateDir(path)
+    } else {
+        Action::CreateFile(path)
+    });
+    Ok(CreateOutcome::Created)
+}
+
+/// Pretends to remove a file or symlink
+pub(crate) fn remove_file(path: &Path) -> Result<RemoveOutcome> {
+    match symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(RemoveOutcome::WasMissing),
+        Err(e) => Err(Error::new(Op::Rmf, path, e)),
+        Ok(m) if m.is_dir() => Err(Error::new(
+            Op::Rmf,
+            path,
+            io::ErrorKind::IsADirectory.into(),
+        )),
+        Ok(_) => {
+            record(Action::RemoveFile(path.to_path_buf()));
+            Ok(RemoveOutcome::Removed)
+        }
+    }
+}
+
+/// Pretends to remove a directory, recording everything a recursive removal would remove
+pub(crate) fn remove_dir(
+    op: Op,
+    path: &Path,
+    recursive: bool,
+    mounts: Mounts,
+) -> Result<RemoveOutcome> {
+    let m = match symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(RemoveOutcome::WasMissing),
+        r => r.ctx(op, path)?,
+    };
+    if !m.is_dir() {
+        if recursive && m.is_symlink() {
+            record(Action::RemoveFile(path.to_path_buf()));
+            return Ok(RemoveOutcome::Removed);
+        }
+        return Err(Error::new(op, path, io::ErrorKind::NotADirectory.into()));
+    }
+
+    if !recursive {
+        if read_dir(path).ctx(op, path)?.next().is_some() {
+            return Ok(RemoveOutcome::SkippedNotEmpty);
+        }
+        record(Action::RemoveDir(path.to_path_buf()));
+        return Ok(RemoveOutcome::Removed);
+    }
+
+    let boundary = match mounts {
+        Mounts::Cross => None,
+        _ => Some(Boundary::new(path)?),
+    };
+    if mounts == Mounts::Refuse && boundary.as_ref().is_some_and(|b| !b.is_clear()) {
+        return Err(Error::new(op, path, crossing()));
+    }
+    if walk(path, boundary.as_ref()).ctx(op, path)? {
+        return Ok(RemoveOutcome::SkippedNotEmpty);
+    }
+    Ok(RemoveOutcome::Removed)
+}
+
+/// Records the removal of a tree, children first, returning whether a mount point was skipped
+fn walk(dir: &Path, boundary: Option<&Boundary>) -> io::Result<bool> {
+    let mut entries = read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+
+    let mut skipped = false;
+    for path in entries {
+        let m = symlink_metadata(&path)?;
+        if !m.is_dir() {
+            record(Action::RemoveFile(path));
+        } else if boundary.is_some_and(|b| b.excludes(m.dev(), m.ino())) {
+            tracing::debug!("Skipping mount point {path:?}");
+            skipped = true;
+        } else {
+            skipped |= walk(&path, boundary)?;
+        }
+    }
+
+    if !skipped {
+        record(Action::RemoveDir(dir.to_path_buf()));
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{
+        Cleanup, Transaction, cp_r, ln_sf, mkdir_p, mkf, mkf_p, mkf_staged, mv, rmdir, rmr,
+        tempdir, tempdir_in, write_atomic,
+    };
+
+    #[test]
+    fn pretend_without_changes() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkf_p(d.join("a/file")).unwrap();
+
+        let dry = DryRun::start();
+        assert!(mkdir_p(d.join("b/c")).unwrap().created());
+        assert!(mkf(d.join("x/file")).is_err());
+        assert_eq!(rmdir(d).unwrap(), RemoveOutcome::SkippedNotEmpty);
+        assert_eq!(rmr(d).unwrap(), RemoveOutcome::Removed);
+        assert_eq!(
+            dry.actions(),
+            [
+                Action::CreateDir(d.join("b")),
+                Action::CreateDir(d.join("b/c")),
+                Action::RemoveFile(d.join("a/file")),
+                Action::RemoveDir(d.join("a")),
+                Action::RemoveDir(d.to_path_buf()),
+            ]
+        );
+        drop(dry);
+
+        assert!(d.join("a/file").exists() && !d.join("b").exists());
+    }
+
+    #[test]
+    fn pretend_in_composite_helpers() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkf_p(d.join("a/file")).unwrap();
+        let listing = || {
+            let mut names = read_dir(d)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let before = listing();
+
+        let dry = DryRun::start();
+        mv(d.join("a"), d.join("b")).unwrap();
+        assert!(mv(d.join("missing"), d.join("b")).is_err());
+        cp_r(d.join("a"), d.join("c")).unwrap();
+        ln_sf("a", d.join("link")).unwrap();
+        write_atomic(d.join("w"), "data").unwrap();
+        let mut staged = mkf_staged(d.join("s")).unwrap();
+        staged.write_all(b"data").unwrap();
+        staged.commit().unwrap();
+
+        let mut tx = Transaction::journaled(d.join("state")).unwrap();
+        assert!(tx.rmf(d.join("a/file")).unwrap().removed());
+        tx.commit().unwrap();
+        assert_eq!(
+            dry.actions(),
+            [
+                Action::Move(d.join("a"), d.join("b")),
+                Action::Copy(d.join("a"), d.join("c")),
+                Action::Symlink(d.join("link"), "a".into()),
+                Action::Write(d.join("w")),
+                Action::Write(d.join("s")),
+                Action::RemoveFile(d.join("a/file")),
+            ]
+        );
+        drop(dry);
+
+        assert_eq!(listing(), before);
+        assert!(d.join("a/file").exists());
+    }
+
+    #[test]
+    fn cleanup_is_never_pretended() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        let mut tx = Transaction::new();
+        tx.mkdir_p(d.join("tx/sub")).unwrap();
+        let mut cleanup = Cleanup::new();
+        cleanup.mkdir(d.join("cleanup")).unwrap();
+
+        let dry = DryRun::start();
+        let temp = tempdir_in(d).unwrap().path().to_path_buf();
+        drop(tx);
+        drop(cleanup);
+        assert_eq!(dry.actions(), []);
+        drop(dry);
+
+        assert!(!temp.exists() && !d.join("tx").exists() && !d.join("cleanup").exists());
+    }
+}
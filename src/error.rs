@@ -0,0 +1,268 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// # A result with a path-aware error.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// # The helper an error came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Op {
+    Mkdir,
+    MkdirP,
+    Mkf,
+    MkfP,
+    MkfStaged,
+    Rmdir,
+    RmdirR,
+    RmdirP,
+    Rmf,
+    RmfPrune,
+    Rm,
+    Rmr,
+    IsDir,
+    Cp,
+    CpR,
+    Mv,
+    LnS,
+    LnSf,
+    WriteAtomic,
+    MountPoints,
+    Resolve,
+    ResolveNonexistent,
+    Kind,
+    Journal,
+    Recover,
+    TempDir,
+    TempFile,
+}
+
+impl Op {
+    /// # Returns the name of the helper.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Mkdir => "mkdir",
+            Self::MkdirP => "mkdir_p",
+            Self::Mkf => "mkf",
+            Self::MkfP => "mkf_p",
+            Self::MkfStaged => "mkf_staged",
+            Self::Rmdir => "rmdir",
+            Self::RmdirR => "rmdir_r",
+            Self::RmdirP => "rmdir_p",
+            Self::Rmf => "rmf",
+            Self::RmfPrune => "rmf_prune",
+            Self::Rm => "rm",
+            Self::Rmr => "rmr",
+            Self::IsDir => "is_dir",
+            Self::Cp => "cp",
+            Self::CpR => "cp_r",
+            Self::Mv => "mv",
+            Self::LnS => "ln_s",
+            Self::LnSf => "ln_sf",
+            Self::WriteAtomic => "write_atomic",
+            Self::MountPoints => "mount_points",
+            Self::Resolve => "resolve",
+            Self::ResolveNonexistent => "resolve_nonexistent",
+            Self::Kind => "kind",
+            Self::Journal => "journal",
+            Self::Recover => "recover",
+            Self::TempDir => "tempdir",
+            Self::TempFile => "tempfile",
+        }
+    }
+
+    /// The start of the message, worded like coreutils
+    const fn verb(self) -> &'static str {
+        match self {
+            Self::Mkdir | Self::MkdirP => "cannot create directory",
+            Self::Mkf | Self::MkfP | Self::MkfStaged => "cannot touch",
+            Self::Rmdir
+            | Self::RmdirR
+            | Self::RmdirP
+            | Self::Rmf
+            | Self::RmfPrune
+            | Self::Rm
+            | Self::Rmr => "cannot remove",
+            Self::IsDir | Self::Kind => "cannot stat",
+            Self::Cp | Self::CpR => "cannot copy",
+            Self::Mv => "cannot move",
+            Self::LnS | Self::LnSf => "failed to create symbolic link",
+            Self::WriteAtomic => "cannot write",
+            Self::MountPoints => "cannot list mount points under",
+            Self::Resolve | Self::ResolveNonexistent => "cannot resolve",
+            Self::Journal => "cannot write journal",
+            Self::Recover => "cannot recover from",
+            Self::TempDir => "cannot create temporary directory in",
+            Self::TempFile => "cannot create temporary file in",
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// # An error from a helper.
+/// Carries the operation, the path(s) involved, and the underlying `io::Error`.
+#[derive(Debug)]
+pub struct Error {
+    op: Op,
+    path: PathBuf,
+    dst: Option<PathBuf>,
+    source: io::Error,
+}
+
+impl Error {
+    /// # Creates an error for an operation on a single path.
+    pub fn new<P>(op: Op, path: P, source: io::Error) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            op,
+            path: path.into(),
+            dst: None,
+            source,
+        }
+    }
+
+    /// # Creates an error for an operation from one path to another.
+    pub fn with_dst<P, Q>(op: Op, src: P, dst: Q, source: io::Error) -> Self
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        Self {
+            op,
+            path: src.into(),
+            dst: Some(dst.into()),
+            source,
+        }
+    }
+
+    /// # Returns the operation that failed.
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    /// # Returns the path the operation failed on.
+    /// For operations with two paths, this is the source.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// # Returns the destination path, if the operation had one.
+    pub fn dst(&self) -> Option<&Path> {
+        self.dst.as_deref()
+    }
+
+    /// # Returns the kind of the underlying `io::Error`.
+    pub fn kind(&self) -> io::ErrorKind {
+        self.source.kind()
+    }
+
+    /// # Returns the underlying `io::Error`.
+    pub fn io(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// # Whether the operation was refused because it would remove a protected path.
+    /// See `protect()`.
+    pub fn is_protected(&self) -> bool {
+        self.source.get_ref().is_some_and(|e| e.is::<Protected>())
+    }
+
+    /// # Consumes the error, returning the underlying `io::Error`.
+    /// The path and operation are lost. Use `io::Error::from` to keep them.
+    pub fn into_io(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} '{}'", self.op.verb(), self.path.display())?;
+        if let Some(dst) = &self.dst {
+            write!(f, " to '{}'", dst.display())?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// # The reason behind refusing to remove a protected path.
+/// Wrapped in an `io::Error` of kind `PermissionDenied`. Holds the protected path.
+#[derive(Debug)]
+pub struct Protected(pub PathBuf);
+
+impl fmt::Display for Protected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' is protected", self.0.display())
+    }
+}
+
+impl std::error::Error for Protected {}
+
+/// Attaches an operation and path(s) to an `io::Result`
+pub(crate) trait Context<T> {
+    fn ctx<P>(self, op: Op, path: P) -> Result<T>
+    where
+        P: AsRef<Path>;
+
+    fn ctx2<P, Q>(self, op: Op, src: P, dst: Q) -> Result<T>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn ctx<P>(self, op: Op, path: P) -> Result<T>
+    where
+        P: AsRef<Path>,
+    {
+        self.map_err(|e| Error::new(op, path.as_ref(), e))
+    }
+
+    fn ctx2<P, Q>(self, op: Op, src: P, dst: Q) -> Result<T>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.map_err(|e| Error::with_dst(op, src.as_ref(), dst.as_ref(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_reads_like_coreutils() {
+        let e = Error::new(Op::Rmf, "/x", io::ErrorKind::PermissionDenied.into());
+        assert_eq!(e.to_string(), "cannot remove '/x': permission denied");
+
+        let e = io::Error::from(Error::with_dst(
+            Op::Mv,
+            "/a",
+            "/b",
+            io::ErrorKind::NotFound.into(),
+        ));
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "cannot move '/a' to '/b': entity not found");
+    }
+}
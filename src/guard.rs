@@ -0,0 +1,104 @@
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use crate::{Error, Op, Result, error::Protected};
+
+static PROTECTED: RwLock<Vec<PathBuf>> = RwLock::new(Vec::new());
+
+/// # Protects a path from recursive removal.
+/// `rmdir_r()` and `rmr()` refuse to remove a protected path or any directory containing one. `/`
+/// and `$HOME` are always protected. Applies process-wide.
+pub fn protect<P>(path: P)
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref().to_path_buf();
+    let mut protected = PROTECTED.write().unwrap_or_else(|e| e.into_inner());
+    if !protected.contains(&path) {
+        protected.push(path);
+    }
+}
+
+/// # Stops protecting a path.
+/// Has no effect on `/` or `$HOME`.
+pub fn unprotect<P>(path: P)
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    PROTECTED
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|p| p != path);
+}
+
+/// Errors if recursively removing `path` would remove a protected path
+pub(crate) fn check(op: Op, path: &Path) -> Result<()> {
+    let target = match canonicalize(path) {
+        Ok(p) => p,
+        // NOTE: Missing paths are left to the removal, which ignores them
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::new(op, path, e)),
+    };
+
+    let protected = PROTECTED.read().unwrap_or_else(|e| e.into_inner());
+    let always = [
+        Some(PathBuf::from("/")),
+        env::var_os("HOME").map(PathBuf::from),
+    ];
+    for p in always
+        .into_iter()
+        .flatten()
+        .chain(protected.iter().cloned())
+    {
+        let p = p.canonicalize().unwrap_or(p);
+        if p.starts_with(&target) {
+            tracing::warn!("Refusing to remove {path:?}, which would remove protected {p:?}");
+            let e = io::Error::new(io::ErrorKind::PermissionDenied, Protected(p));
+            return Err(Error::new(op, path, e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Canonicalizes `path` without following a symlink in the last component, since removal doesn't
+fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.canonicalize();
+    };
+
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    path.symlink_metadata()?;
+    Ok(parent.canonicalize()?.join(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{mkdir_p, rmdir_r, rmr, tempdir};
+
+    #[test]
+    fn refuse_protected_paths() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        let keep = d.join("keep");
+        mkdir_p(&keep).unwrap();
+        protect(&keep);
+
+        let e = rmdir_r(keep.join("../keep")).unwrap_err();
+        assert!(e.is_protected());
+        assert!(rmr(d).unwrap_err().is_protected());
+        assert!(keep.exists());
+
+        unprotect(&keep);
+        assert!(rmr(d).is_ok());
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    ffi::OsStr,
+    fs::{File, OpenOptions, TryLockError, read_dir, remove_file, rename, symlink_metadata},
+    io::{self, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf, absolute},
+};
+
+use crate::{
+    Action, Context, Error, Op, Result,
+    atomic::{rename_noreplace, sync_parent},
+    dry, mkdir_p, rm, rmr, tmp_sibling,
+};
+
+/// The first line of every journal
+///
+/// Records follow as NUL-terminated fields: `create`, path; `stash`, path, stash; or `commit`. A
+/// record cut short by a crash is ignored.
+const HEADER: &[u8] = b"fshelpers journal v1\n";
+
+const EXTENSION: &str = "journal";
+
+/// A record in a journal
+#[derive(Debug, PartialEq, Eq)]
+enum Record {
+    /// A missing path is about to be created
+    Create(PathBuf),
+    /// A path is about to be moved aside to a stash
+    Stash(PathBuf, PathBuf),
+    /// The transaction was committed, so stashes are to be deleted
+    Commit,
+}
+
+/// A write-ahead journal file
+///
+/// Every record is synced before the change it describes is made. The file is locked for as long
+/// as this lives, so `recover()` leaves it alone.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Starts a new journal in `dir`, creating it if needed
+    ///
+    /// The journal is written and locked under a temporary name first, so `recover()` never finds
+    /// it unlocked or without a header.
+    pub(crate) fn create(dir: &Path) -> Result<Self> {
+        mkdir_p(dir)?;
+        let (path, file) = tmp_sibling(&dir.join("fshelpers"), |tmp| {
+            let mut file = OpenOptions::new().write(true).create_new(true).open(tmp)?;
+            let path = tmp.with_extension(EXTENSION);
+            file.try_lock()
+                .map_err(io::Error::from)
+                .and_then(|()| file.write_all(HEADER))
+                .and_then(|()| file.sync_all())
+                .and_then(|()| rename_noreplace(tmp, &path))
+                .inspect_err(|_| {
+                    let _ = remove_file(tmp);
+                })?;
+            Ok((path, file))
+        })
+        .ctx(Op::Journal, dir)?
+        .1;
+        sync_parent(&path).ctx(Op::Journal, &path)?;
+        tracing::debug!("Journaling to {path:?}");
+        Ok(Self { path, file })
+    }
+
+    /// Records that `path` is about to be created
+    pub(crate) fn create_intent(&mut self, path: &Path) -> Result<()> {
+        self.append(&Record::Create(absolute(path).ctx(Op::Journal, path)?))
+    }
+
+    /// Records that `path` is about to be moved aside to `stash`
+    pub(crate) fn stash_intent(&mut self, path: &Path, stash: &Path) -> Result<()> {
+        let path = absolute(path).ctx(Op::Journal, path)?;
+        let stash = absolute(stash).ctx(Op::Journal, stash)?;
+        self.append(&Record::Stash(path, stash))
+    }
+
+    /// Records that the transaction was committed
+    pub(crate) fn commit(&mut self) -> Result<()> {
+        self.append(&Record::Commit)
+    }
+
+    /// Deletes the journal once there's nothing left to recover
+    ///
+    /// The lock is only released once the journal is gone.
+    pub(crate) fn finish(self) -> Result<()> {
+        remove_file(&self.path)
+            .and_then(|()| sync_parent(&self.path))
+            .ctx(Op::Journal, &self.path)
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut field = |f: &[u8]| {
+            buf.extend_from_slice(f);
+            buf.push(0);
+        };
+        match record {
+            Record::Create(p) => {
+                field(b"create");
+                field(p.as_os_str().as_bytes());
+            }
+            Record::Stash(p, s) => {
+                field(b"stash");
+                field(p.as_os_str().as_bytes());
+                field(s.as_os_str().as_bytes());
+            }
+            Record::Commit => field(b"commit"),
+        }
+        self.file
+            .write_all(&buf)
+            .and_then(|()| self.file.sync_data())
+            .ctx(Op::Journal, &self.path)
+    }
+}
+
+/// Parses a journal, dropping a trailing record cut short by a crash
+fn parse(data: &[u8]) -> io::Result<Vec<Record>> {
+    let Some(body) = data.strip_prefix(HEADER) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a version 1 journal",
+        ));
+    };
+
+    // NOTE: The last piece is unterminated, so it's always incomplete or empty
+    let mut fields = body.split(|&b| b == 0).collect::<Vec<_>>();
+    fields.pop();
+    let mut fields = fields.into_iter();
+    let path = |f: &[u8]| PathBuf::from(OsStr::from_bytes(f));
+
+    let mut records = Vec::new();
+    while let Some(tag) = fields.next() {
+        let record = match tag {
+            b"create" => fields.next().map(|p| Record::Create(path(p))),
+            b"stash" => fields
+                .next()
+                .zip(fields.next())
+                .map(|(p, s)| Record::Stash(path(p), path(s))),
+            b"commit" => Some(Record::Commit),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown journal record",
+                ));
+            }
+        };
+        match record {
+            Some(r) => records.push(r),
+            None => break,
+        }
+    }
+    Ok(records)
+}
+
+/// # Finishes or undoes transactions interrupted by a crash.
+/// Reads every journal left in `journal_dir` by a journaled `Transaction`. Committed transactions
+/// are finished by deleting what they moved aside; the rest are rolled back. Each journal is deleted
+/// once it's been dealt with. Journals of transactions still underway, in this process or another,
+/// are locked and skipped. Returns how many were recovered.
+pub fn recover<P>(journal_dir: P) -> Result<usize>
+where
+    P: AsRef<Path>,
+{
+    let dir = journal_dir.as_ref();
+    let entries = match read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        r => r.ctx(Op::Recover, dir)?,
+    };
+
+    let mut journals = entries
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .ctx(Op::Recover, dir)?;
+    journals.retain(|p| p.extension() == Some(OsStr::new(EXTENSION)));
+    journals.sort();
+
+    let mut recovered = 0;
+    for journal in &journals {
+        // NOTE: The lock is held until the journal is deleted
+        let Some((_lock, records)) = claim(journal).ctx(Op::Recover, journal)? else {
+            tracing::debug!("Skipping {journal:?}, which is in use or finished");
+            continue;
+        };
+        replay(&records)?;
+        recovered += 1;
+        if dry::active() {
+            dry::record(Action::RemoveFile(journal.clone()));
+            continue;
+        }
+        remove_file(journal).ctx(Op::Recover, journal)?;
+        tracing::info!("Recovered from {journal:?}");
+    }
+    if recovered > 0 && !dry::active() {
+        sync_parent(&dir.join(EXTENSION)).ctx(Op::Recover, dir)?;
+    }
+    Ok(recovered)
+}
+
+/// Locks and reads a journal, unless a transaction still holds it or has since deleted it
+fn claim(journal: &Path) -> io::Result<Option<(File, Vec<Record>)>> {
+    let mut file = match File::open(journal) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        r => r?,
+    };
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => return Ok(None),
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    // NOTE: A transaction that finished after the open unlinked the journal before unlocking it
+    if file.metadata()?.nlink() == 0 {
+        return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(Some((file, parse(&data)?)))
+}
+
+/// Finishes a committed transaction or rolls back an uncommitted one
+fn replay(records: &[Record]) -> Result<()> {
+    if records.contains(&Record::Commit) {
+        for r in records {
+            if let Record::Stash(_, stash) = r {
+                rmr(stash)?;
+            }
+        }
+        return Ok(());
+    }
+
+    for r in records.iter().rev() {
+        match r {
+            Record::Create(path) => {
+                rm(path)?;
+            }
+            // NOTE: A missing stash means the move never happened
+            Record::Stash(path, stash) if symlink_metadata(stash).is_ok() => {
+                if dry::active() {
+                    dry::record(Action::Move(stash.clone(), path.clone()));
+                } else {
+                    rename(stash, path).map_err(|e| Error::with_dst(Op::Mv, stash, path, e))?;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Transaction, mkf_p, tempdir};
+
+    #[test]
+    fn recover_interrupted_transactions() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        let state = d.join("state");
+        mkf_p(d.join("old")).unwrap();
+
+        let mut tx = Transaction::journaled(&state).unwrap();
+        tx.mkf_p(d.join("a/new")).unwrap();
+        tx.rmr(d.join("old")).unwrap();
+        tx.crash();
+        assert!(!d.join("old").exists());
+
+        // NOTE: A transaction still underway is locked, so it's left alone
+        let mut live = Transaction::journaled(&state).unwrap();
+        live.mkf(d.join("live")).unwrap();
+
+        assert_eq!(recover(&state).unwrap(), 1);
+        assert!(d.join("old").exists() && !d.join("a").exists());
+        assert!(d.join("live").exists());
+        assert_eq!(recover(&state).unwrap(), 0);
+        drop(live);
+        assert!(!d.join("live").exists());
+        assert_eq!(read_dir(&state).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn parse_drops_torn_records() {
+        let mut data = HEADER.to_vec();
+        data.extend_from_slice(b"create\0/a\0stash\0/b\0/.b.tmp\0commit\0stash\0/c");
+        assert_eq!(
+            parse(&data).unwrap(),
+            [
+                Record::Create("/a".into()),
+                Record::Stash("/b".into(), "/.b.tmp".into()),
+                Record::Commit,
+            ]
+        );
+        assert!(parse(b"fshelpers journal v2\n").is_err());
+    }
+}
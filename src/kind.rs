@@ -0,0 +1,152 @@
+use std::{
+    fs::{FileType, metadata, symlink_metadata},
+    io,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
+
+use crate::{Error, Op, Result};
+
+/// # What a path is.
+/// Returned by `kind()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    File,
+    Dir,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    /// A symlink, holding the kind of what it ultimately points to, or `None` if it dangles
+    Symlink(Option<TargetKind>),
+}
+
+/// # What a symlink ultimately points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TargetKind {
+    File,
+    Dir,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl FileKind {
+    /// # Whether this is a directory or a symlink to one.
+    pub const fn is_dir(self) -> bool {
+        matches!(self, Self::Dir | Self::Symlink(Some(TargetKind::Dir)))
+    }
+
+    /// # Whether this is a regular file or a symlink to one.
+    pub const fn is_file(self) -> bool {
+        matches!(self, Self::File | Self::Symlink(Some(TargetKind::File)))
+    }
+
+    /// # Whether this is a symlink, dangling or not.
+    pub const fn is_symlink(self) -> bool {
+        matches!(self, Self::Symlink(_))
+    }
+
+    /// # Whether this is a symlink that points to nothing.
+    pub const fn is_dangling(self) -> bool {
+        matches!(self, Self::Symlink(None))
+    }
+}
+
+impl From<FileType> for TargetKind {
+    fn from(ft: FileType) -> Self {
+        if ft.is_dir() {
+            Self::Dir
+        } else if ft.is_fifo() {
+            Self::Fifo
+        } else if ft.is_socket() {
+            Self::Socket
+        } else if ft.is_block_device() {
+            Self::BlockDevice
+        } else if ft.is_char_device() {
+            Self::CharDevice
+        } else {
+            Self::File
+        }
+    }
+}
+
+impl From<TargetKind> for FileKind {
+    fn from(k: TargetKind) -> Self {
+        match k {
+            TargetKind::File => Self::File,
+            TargetKind::Dir => Self::Dir,
+            TargetKind::Fifo => Self::Fifo,
+            TargetKind::Socket => Self::Socket,
+            TargetKind::BlockDevice => Self::BlockDevice,
+            TargetKind::CharDevice => Self::CharDevice,
+        }
+    }
+}
+
+/// Whether an error means there's nothing at the path
+fn is_missing(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    ) || e.raw_os_error() == Some(libc::ELOOP)
+}
+
+/// # Classifies a path.
+/// Does not follow a symlink at `path` itself, but reports what it ultimately points to. Returns
+/// `None` for missing paths.
+pub fn kind<P>(path: P) -> Result<Option<FileKind>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let ft = match symlink_metadata(path) {
+        Ok(m) => m.file_type(),
+        Err(e) if is_missing(&e) => return Ok(None),
+        Err(e) => return Err(Error::new(Op::Kind, path, e)),
+    };
+
+    if !ft.is_symlink() {
+        return Ok(Some(TargetKind::from(ft).into()));
+    }
+
+    // NOTE: Loops count as dangling
+    match metadata(path) {
+        Ok(m) => Ok(Some(FileKind::Symlink(Some(m.file_type().into())))),
+        Err(e) if is_missing(&e) => Ok(Some(FileKind::Symlink(None))),
+        Err(e) => Err(Error::new(Op::Kind, path, e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::{fs::symlink, net::UnixListener};
+
+    use super::*;
+    use crate::{mkf_p, tempdir};
+
+    #[test]
+    fn classify() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkf_p(d.join("file")).unwrap();
+        symlink("file", d.join("link")).unwrap();
+        symlink("missing", d.join("dangling")).unwrap();
+        let _listener = UnixListener::bind(d.join("sock")).unwrap();
+
+        assert_eq!(kind(d).unwrap(), Some(FileKind::Dir));
+        assert_eq!(kind(d.join("file")).unwrap(), Some(FileKind::File));
+        assert_eq!(
+            kind(d.join("link")).unwrap(),
+            Some(FileKind::Symlink(Some(TargetKind::File)))
+        );
+        assert_eq!(
+            kind(d.join("dangling")).unwrap(),
+            Some(FileKind::Symlink(None))
+        );
+        assert_eq!(kind(d.join("sock")).unwrap(), Some(FileKind::Socket));
+        assert_eq!(kind("/dev/null").unwrap(), Some(FileKind::CharDevice));
+        assert_eq!(kind(d.join("missing")).unwrap(), None);
+    }
+}
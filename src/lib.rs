@@ -1,133 +1,1044 @@
 use std::{
-    fs::{File, create_dir, create_dir_all, read_link, remove_dir, remove_dir_all, remove_file},
+    fs::{
+        DirBuilder, OpenOptions, Permissions, remove_dir, remove_file, set_permissions,
+        symlink_metadata,
+    },
     io,
-    path::Path,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process,
 };
 
-use permitit::Permit;
+/// # Ignores some kinds of IO error.
+/// Works on any `io::Result<()>`, giving `Ok(())` for errors of the listed kinds and logging which
+/// kind was permitted. For other results, `=> default` gives `Ok(default)` for permitted errors
+/// instead; map the result to `Some` and pass `=> None` to tell the two apart.
+///
+/// ```
+/// use std::fs::{read_to_string, rename};
+///
+/// use fshelpers::permit;
+///
+/// permit!(rename("/tmp/missing", "/tmp/moved"), NotFound)?;
+/// let s = permit!(read_to_string("/tmp/missing") => String::new(), NotFound, NotADirectory)?;
+/// assert!(s.is_empty());
+/// let s = permit!(read_to_string("/tmp/missing").map(Some) => None, NotFound)?;
+/// assert_eq!(s, None);
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[macro_export]
+macro_rules! permit {
+    ($f:expr => $default:expr, $($ioe:ident),+ $(,)?) => {
+        $crate::__permit_kinds(
+            $f,
+            &[$(::std::io::ErrorKind::$ioe),+],
+            ::std::stringify!($f),
+        )
+        .map(|o| o.unwrap_or_else(|| $default))
+    };
+    ($f:expr, $($ioe:ident),+ $(,)?) => {
+        $crate::permit!($f => (), $($ioe),+)
+    };
+}
 
 macro_rules! iopermit {
-    ($f:expr, $($ioe:ident),+ $(,)?) => {{
+    ($policy:expr; $f:expr => $ok:expr, $($ioe:ident => $out:expr),+ $(,)?) => {{
         use std::io::ErrorKind as IOE;
-        let initial = $f;
-        let mut f = initial;
-        $(
-            f = f.permit(|e| {
-                tracing::debug!("Permitting {:?} for {:?}", IOE::$ioe, stringify!($f));
-                e.kind() == IOE::$ioe
-            });
-        )+
-        f
+        $crate::policy::permit(
+            $policy,
+            $f.map(drop),
+            $ok,
+            &[$((IOE::$ioe, $out)),+],
+            stringify!($f),
+        )
     }};
 }
 
+mod atomic;
+mod cleanup;
+mod copy;
+mod dry;
+mod error;
+mod guard;
+mod journal;
+mod kind;
+mod link;
+mod mounts;
+mod policy;
+mod remove;
+mod staged;
+mod temp;
+mod transaction;
+
+pub use atomic::*;
+pub use cleanup::Cleanup;
+pub use copy::*;
+pub use dry::{Action, DryRun};
+use error::Context;
+pub use error::{Error, Op, Protected, Result};
+pub use guard::{protect, unprotect};
+pub use journal::recover;
+pub use kind::{FileKind, TargetKind, kind};
+pub use link::*;
+pub use mounts::{Mounts, mount_points};
+#[doc(hidden)]
+pub use policy::permit_kinds as __permit_kinds;
+pub use policy::{PermitPolicy, PolicyGuard};
+use remove::remove_tree;
+pub use staged::StagedFile;
+pub use temp::{TempDir, TempFile, tempdir, tempdir_in, tempfile, tempfile_in};
+pub use transaction::Transaction;
+
+/// # What a creation helper did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CreateOutcome {
+    /// The path was created
+    Created,
+    /// The path already existed and was left alone
+    AlreadyExisted,
+    /// An error the `PermitPolicy` permits, but the helper doesn't by default, was ignored
+    Ignored(io::ErrorKind),
+}
+
+impl CreateOutcome {
+    /// # Whether anything was created.
+    pub const fn created(self) -> bool {
+        matches!(self, Self::Created)
+    }
+}
+
+/// # What a removal helper did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RemoveOutcome {
+    /// The path was removed
+    Removed,
+    /// The path did not exist
+    WasMissing,
+    /// The path was a populated directory and was left alone
+    SkippedNotEmpty,
+    /// An error the `PermitPolicy` permits, but the helper doesn't by default, was ignored
+    Ignored(io::ErrorKind),
+}
+
+impl RemoveOutcome {
+    /// # Whether anything was removed.
+    pub const fn removed(self) -> bool {
+        matches!(self, Self::Removed)
+    }
+}
+
+/// Errors if `path` already existed but isn't the kind of entry the helper creates
+fn check_existing(
+    op: Op,
+    path: &Path,
+    outcome: CreateOutcome,
+    want_dir: bool,
+    exist_ok: bool,
+    strict: bool,
+    follow_symlinks: bool,
+) -> Result<CreateOutcome> {
+    if outcome != CreateOutcome::AlreadyExisted {
+        return Ok(outcome);
+    }
+    if !exist_ok {
+        return Err(Error::new(op, path, io::ErrorKind::AlreadyExists.into()));
+    }
+    if !strict {
+        return Ok(outcome);
+    }
+
+    // NOTE: Dangling symlinks, and symlinks when not following them, are never of the right kind
+    let k = kind(path)
+        .ok()
+        .flatten()
+        .filter(|k| follow_symlinks || !k.is_symlink());
+    let (is_dir, is_file) = (
+        k.is_some_and(FileKind::is_dir),
+        k.is_some_and(FileKind::is_file),
+    );
+    let e = match (want_dir, is_dir, is_file) {
+        (true, true, _) | (false, false, true) => return Ok(outcome),
+        (true, false, _) => io::Error::new(
+            io::ErrorKind::NotADirectory,
+            "exists and is not a directory",
+        ),
+        (false, true, _) => {
+            io::Error::new(io::ErrorKind::IsADirectory, "exists and is a directory")
+        }
+        (false, false, false) => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "exists and is not a regular file",
+        ),
+    };
+    Err(Error::new(op, path, e))
+}
+
+/// # Options for creating directories.
+/// By default, parents are not created, existing directories and symlinks to them are ignored, and
+/// the default mode filtered by umask is used.
+#[derive(Clone, Debug)]
+pub struct MkdirOptions {
+    parents: bool,
+    exist_ok: bool,
+    strict: bool,
+    follow_symlinks: bool,
+    mode: Option<u32>,
+    parent_mode: Option<u32>,
+    enforce_mode: bool,
+    policy: Option<PermitPolicy>,
+}
+
+impl Default for MkdirOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MkdirOptions {
+    /// # Creates the default directory creation options.
+    pub const fn new() -> Self {
+        Self {
+            parents: false,
+            exist_ok: true,
+            strict: true,
+            follow_symlinks: true,
+            mode: None,
+            parent_mode: None,
+            enforce_mode: false,
+            policy: None,
+        }
+    }
+
+    /// # Whether to create missing parents.
+    pub const fn parents(mut self, yes: bool) -> Self {
+        self.parents = yes;
+        self
+    }
+
+    /// # Whether to ignore attempts to create a directory that already exists.
+    /// Existing parents are always fine.
+    pub const fn exist_ok(mut self, yes: bool) -> Self {
+        self.exist_ok = yes;
+        self
+    }
+
+    /// # Whether to check what is in the way.
+    /// Enabled by default, so only an existing directory is ignored. Disabling it ignores anything
+    /// that already exists.
+    pub const fn strict_exists(mut self, yes: bool) -> Self {
+        self.strict = yes;
+        self
+    }
+
+    /// # Whether an existing symlink to a directory counts as an existing directory.
+    pub const fn follow_symlinks(mut self, yes: bool) -> Self {
+        self.follow_symlinks = yes;
+        self
+    }
+
+    /// # The mode to create the directory with.
+    /// Applied exactly, regardless of umask.
+    pub const fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// # The mode to create missing parents with.
+    /// Applied exactly, regardless of umask, and only to parents that get created.
+    pub const fn parent_mode(mut self, mode: u32) -> Self {
+        self.parent_mode = Some(mode);
+        self
+    }
+
+    /// # Whether to also apply the mode if the directory already exists.
+    pub const fn enforce_mode(mut self, yes: bool) -> Self {
+        self.enforce_mode = yes;
+        self
+    }
+
+    /// # Which errors to ignore, instead of the installed policy.
+    /// Also applies to creating parents.
+    pub fn policy(mut self, policy: PermitPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// # Creates a directory with these options.
+    /// See `mkdir()` and `mkdir_p()`.
+    pub fn mkdir<P>(&self, dir: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        use CreateOutcome::*;
+        let dir = dir.as_ref();
+        let op = if self.parents { Op::MkdirP } else { Op::Mkdir };
+        if self.parents {
+            self.mkparent(dir)?;
+        }
+        if dry::active() {
+            let outcome = dry::create(op, dir, true, self.parents)?;
+            return check_existing(
+                op,
+                dir,
+                outcome,
+                true,
+                self.exist_ok,
+                self.strict,
+                self.follow_symlinks,
+            );
+        }
+
+        let mut builder = DirBuilder::new();
+        if let Some(mode) = self.mode {
+            builder.mode(mode);
+        }
+        let outcome = iopermit!(
+            self.policy.as_ref();
+            builder.create(dir) => Created,
+            AlreadyExists => AlreadyExisted,
+        )
+        .ctx(op, dir)?;
+        let outcome = check_existing(
+            op,
+            dir,
+            outcome,
+            true,
+            self.exist_ok,
+            self.strict,
+            self.follow_symlinks,
+        )?;
+        apply_mode(op, dir, self.mode, outcome, self.enforce_mode)?;
+        Ok(outcome)
+    }
+
+    /// Creates the parent of `path` if it is missing
+    fn mkparent(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            // NOTE: This if prevents unnecessary logs
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                let opts = Self {
+                    parents: true,
+                    exist_ok: true,
+                    strict: self.strict,
+                    follow_symlinks: true,
+                    mode: self.parent_mode,
+                    parent_mode: self.parent_mode,
+                    enforce_mode: false,
+                    policy: self.policy.clone(),
+                };
+                opts.mkdir(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// # Creates a directory with these options, listing every directory created.
+    /// Missing parents are created one at a time, so the list is exact: outermost first, ending with
+    /// `dir` if it was created. Removing them in reverse with `rmdir()` undoes the call. If creation
+    /// fails partway, the directories already created are removed before returning the error.
+    pub fn mkdir_created<P>(&self, dir: P) -> Result<Vec<PathBuf>>
+    where
+        P: AsRef<Path>,
+    {
+        let mut created = Vec::new();
+        let result = self.mkdir_into(dir.as_ref(), &mut created);
+        undo_created(result, created).map(|((), created)| created)
+    }
+
+    /// Creates a directory, pushing every directory created onto `created`
+    fn mkdir_into(&self, dir: &Path, created: &mut Vec<PathBuf>) -> Result<()> {
+        if self.parents {
+            self.mkparent_into(dir, created)?;
+        }
+        if self.mkdir(dir)?.created() {
+            created.push(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Creates the missing parents of `path` one at a time, pushing those created onto `created`
+    fn mkparent_into(&self, path: &Path, created: &mut Vec<PathBuf>) -> Result<()> {
+        let opts = Self {
+            parents: false,
+            exist_ok: true,
+            strict: self.strict,
+            follow_symlinks: true,
+            mode: self.parent_mode,
+            parent_mode: None,
+            enforce_mode: false,
+            policy: self.policy.clone(),
+        };
+        for dir in missing_parents(path) {
+            if opts.mkdir(dir)?.created() {
+                created.push(dir.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// # Options for creating files.
+/// By default, parents are not created, existing files and symlinks to them are ignored, and the
+/// default mode filtered by umask is used.
+#[derive(Clone, Debug)]
+pub struct MkfOptions {
+    parents: bool,
+    exist_ok: bool,
+    strict: bool,
+    follow_symlinks: bool,
+    mode: Option<u32>,
+    parent_mode: Option<u32>,
+    enforce_mode: bool,
+    policy: Option<PermitPolicy>,
+}
+
+impl Default for MkfOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MkfOptions {
+    /// # Creates the default file creation options.
+    pub const fn new() -> Self {
+        Self {
+            parents: false,
+            exist_ok: true,
+            strict: true,
+            follow_symlinks: true,
+            mode: None,
+            parent_mode: None,
+            enforce_mode: false,
+            policy: None,
+        }
+    }
+
+    /// # Whether to create missing parents.
+    pub const fn parents(mut self, yes: bool) -> Self {
+        self.parents = yes;
+        self
+    }
+
+    /// # Whether to ignore attempts to create a file that already exists.
+    pub const fn exist_ok(mut self, yes: bool) -> Self {
+        self.exist_ok = yes;
+        self
+    }
+
+    /// # Whether to check what is in the way.
+    /// Enabled by default, so only an existing regular file is ignored. Disabling it ignores
+    /// anything that already exists.
+    pub const fn strict_exists(mut self, yes: bool) -> Self {
+        self.strict = yes;
+        self
+    }
+
+    /// # Whether an existing symlink to a file counts as an existing file.
+    pub const fn follow_symlinks(mut self, yes: bool) -> Self {
+        self.follow_symlinks = yes;
+        self
+    }
+
+    /// # The mode to create the file with.
+    /// Applied exactly, regardless of umask.
+    pub const fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// # The mode to create missing parents with.
+    /// Applied exactly, regardless of umask, and only to parents that get created.
+    pub const fn parent_mode(mut self, mode: u32) -> Self {
+        self.parent_mode = Some(mode);
+        self
+    }
+
+    /// # Whether to also apply the mode if the file already exists.
+    pub const fn enforce_mode(mut self, yes: bool) -> Self {
+        self.enforce_mode = yes;
+        self
+    }
+
+    /// # Which errors to ignore, instead of the installed policy.
+    /// Also applies to creating parents.
+    pub fn policy(mut self, policy: PermitPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// # Creates a file with these options.
+    /// See `mkf()` and `mkf_p()`.
+    pub fn mkf<P>(&self, file: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        use CreateOutcome::*;
+        let file = file.as_ref();
+        let op = if self.parents { Op::MkfP } else { Op::Mkf };
+        if self.parents {
+            self.parent_options().mkparent(file)?;
+        }
+        if dry::active() {
+            let outcome = dry::create(op, file, false, self.parents)?;
+            return check_existing(
+                op,
+                file,
+                outcome,
+                false,
+                self.exist_ok,
+                self.strict,
+                self.follow_symlinks,
+            );
+        }
+
+        let mut opts = OpenOptions::new();
+        opts.write(true).create_new(true);
+        if let Some(mode) = self.mode {
+            opts.mode(mode);
+        }
+        let outcome = iopermit!(
+            self.policy.as_ref();
+            opts.open(file) => Created,
+            AlreadyExists => AlreadyExisted,
+        )
+        .ctx(op, file)?;
+        let outcome = check_existing(
+            op,
+            file,
+            outcome,
+            false,
+            self.exist_ok,
+            self.strict,
+            self.follow_symlinks,
+        )?;
+        apply_mode(op, file, self.mode, outcome, self.enforce_mode)?;
+        Ok(outcome)
+    }
+
+    /// # Stages a file with these options.
+    /// See `mkf_staged()`. Only `parents`, `mode` and `parent_mode` apply.
+    pub fn stage<P>(&self, file: P) -> Result<StagedFile>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        if self.parents {
+            self.parent_options().mkparent(file)?;
+        }
+        staged::open(file, self.mode)
+    }
+
+    /// # Creates a file with these options, listing every parent directory created.
+    /// Parents are created like `MkdirOptions::mkdir_created()` and listed outermost first. If
+    /// creating the file fails, the parents created are removed before returning the error.
+    pub fn mkf_created<P>(&self, file: P) -> Result<(CreateOutcome, Vec<PathBuf>)>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        let mut created = Vec::new();
+        let mut result = Ok(());
+        if self.parents {
+            result = self.parent_options().mkparent_into(file, &mut created);
+        }
+        let result = result.and_then(|()| self.mkf(file));
+        undo_created(result, created)
+    }
+
+    /// The options for creating missing parents
+    fn parent_options(&self) -> MkdirOptions {
+        let mut parents = MkdirOptions::new().strict_exists(self.strict);
+        if let Some(mode) = self.parent_mode {
+            parents = parents.parent_mode(mode);
+        }
+        if let Some(policy) = &self.policy {
+            parents = parents.policy(policy.clone());
+        }
+        parents
+    }
+}
+
+/// Pairs a result with the directories created for it, or removes them, newest first, on error
+fn undo_created<T>(result: Result<T>, created: Vec<PathBuf>) -> Result<(T, Vec<PathBuf>)> {
+    let e = match result {
+        Ok(v) => return Ok((v, created)),
+        Err(e) => e,
+    };
+    for dir in created.iter().rev() {
+        if let Err(e) = rmdir(dir) {
+            tracing::warn!("Failed to remove {dir:?} after a failed creation: {e}");
+        }
+    }
+    Err(e)
+}
+
+/// Sets the exact mode of `path` if it was created, or already existed and `enforce` is set
+fn apply_mode(
+    op: Op,
+    path: &Path,
+    mode: Option<u32>,
+    outcome: CreateOutcome,
+    enforce: bool,
+) -> Result<()> {
+    let apply = match outcome {
+        CreateOutcome::Created => true,
+        CreateOutcome::AlreadyExisted => enforce,
+        CreateOutcome::Ignored(_) => false,
+    };
+    match mode {
+        Some(mode) if apply => set_permissions(path, Permissions::from_mode(mode)).ctx(op, path),
+        _ => Ok(()),
+    }
+}
+
 /// # Creates a directory.
 /// Existing directories are ignored. Does not recurse.
-pub fn mkdir<P>(dir: P) -> io::Result<()>
+pub fn mkdir<P>(dir: P) -> Result<CreateOutcome>
+where
+    P: AsRef<Path>,
+{
+    MkdirOptions::new().mkdir(dir)
+}
+
+/// # Creates a directory with an exact mode.
+/// Behaves like `mkdir()` otherwise. See `MkdirOptions` for more control.
+pub fn mkdir_mode<P>(dir: P, mode: u32) -> Result<CreateOutcome>
 where
     P: AsRef<Path>,
 {
-    iopermit!(create_dir(dir), AlreadyExists)
+    MkdirOptions::new().mode(mode).mkdir(dir)
 }
 
 /// # Creates a file.
 /// Ignores attempts to create a file that already exists. Roughly corresponds to touch.
-pub fn mkf<P>(file: P) -> io::Result<()>
+pub fn mkf<P>(file: P) -> Result<CreateOutcome>
 where
     P: AsRef<Path>,
 {
-    iopermit!(File::create_new(file).map(drop), AlreadyExists)
+    MkfOptions::new().mkf(file)
+}
+
+/// # Creates a file with an exact mode.
+/// Behaves like `mkf()` otherwise. See `MkfOptions` for more control.
+pub fn mkf_mode<P>(file: P, mode: u32) -> Result<CreateOutcome>
+where
+    P: AsRef<Path>,
+{
+    MkfOptions::new().mode(mode).mkf(file)
+}
+
+/// # Creates a file, with parents, listing every parent directory created.
+/// The list is outermost first. See `MkfOptions::mkf_created()`.
+pub fn mkf_p_created<P>(file: P) -> Result<(CreateOutcome, Vec<PathBuf>)>
+where
+    P: AsRef<Path>,
+{
+    MkfOptions::new().parents(true).mkf_created(file)
+}
+
+/// # Starts writing a file that only appears once complete.
+/// The returned `StagedFile` is unnamed where `O_TMPFILE` is supported, or a hidden temporary
+/// sibling otherwise. Committing it atomically replaces anything at `file`. See `MkfOptions` for
+/// parents and modes.
+pub fn mkf_staged<P>(file: P) -> Result<StagedFile>
+where
+    P: AsRef<Path>,
+{
+    MkfOptions::new().stage(file)
 }
 
 /// # Creates a file, with parents.
 /// Ignores attempts to create a file that already exists.
-pub fn mkf_p<P>(file: P) -> io::Result<()>
+pub fn mkf_p<P>(file: P) -> Result<CreateOutcome>
 where
     P: AsRef<Path>,
 {
-    if let Some(parent) = file.as_ref().parent() {
-        // NOTE: This if prevents unnecessary logs
-        if !parent.exists() {
-            mkdir_p(parent)?
+    MkfOptions::new().parents(true).mkf(file)
+}
+
+/// Creates the parent of `path` if it is missing
+fn mkparent(path: &Path) -> Result<()> {
+    MkdirOptions::new().mkparent(path)
+}
+
+/// Lists the missing ancestors of `path`, outermost first
+fn missing_parents(path: &Path) -> Vec<&Path> {
+    let mut missing = path
+        .ancestors()
+        .skip(1)
+        .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+        .collect::<Vec<_>>();
+    missing.reverse();
+    missing
+}
+
+/// Calls `create` on random hidden paths next to `path` until one doesn't already exist
+///
+/// Returns the path `create` succeeded on, for staging a replacement.
+fn tmp_sibling<F, T>(path: &Path, mut create: F) -> io::Result<(PathBuf, T)>
+where
+    F: FnMut(&Path) -> io::Result<T>,
+{
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    for _ in 0..temp::ATTEMPTS {
+        let tmp = path.with_file_name(format!(
+            ".{name}.{}.{:016x}.tmp",
+            process::id(),
+            temp::random()
+        ));
+        match create(&tmp) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            r => return r.map(|v| (tmp, v)),
         }
     }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "ran out of unique names",
+    ))
+}
 
-    iopermit!(File::create_new(file).map(drop), AlreadyExists)
+/// Fails with `AlreadyExists` unless `path` is free, for reserving names with `tmp_sibling()`
+fn vacant(path: &Path) -> io::Result<()> {
+    match symlink_metadata(path) {
+        Ok(_) => Err(io::ErrorKind::AlreadyExists.into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
 }
 
 /// # Creates a directory and all its parents.
-/// Existing directores are ignored
-pub fn mkdir_p<P>(dir: P) -> io::Result<()>
+/// Existing directores are ignored. The outcome describes `dir` itself, not its parents.
+pub fn mkdir_p<P>(dir: P) -> Result<CreateOutcome>
 where
     P: AsRef<Path>,
 {
-    iopermit!(create_dir_all(dir), AlreadyExists)
+    MkdirOptions::new().parents(true).mkdir(dir)
+}
+
+/// # Creates a directory and all its parents, listing every directory created.
+/// The list is outermost first and includes `dir` if it was created, so removing the directories in
+/// reverse with `rmdir()` undoes the call. See `MkdirOptions::mkdir_created()`.
+pub fn mkdir_p_created<P>(dir: P) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    MkdirOptions::new().parents(true).mkdir_created(dir)
+}
+
+/// # Creates a directory and all its parents, giving the directory an exact mode.
+/// Parents get the default mode. Behaves like `mkdir_p()` otherwise. See `MkdirOptions` for more
+/// control.
+pub fn mkdir_p_mode<P>(dir: P, mode: u32) -> Result<CreateOutcome>
+where
+    P: AsRef<Path>,
+{
+    MkdirOptions::new().parents(true).mode(mode).mkdir(dir)
+}
+
+/// # Options for removal.
+/// By default, removal is not recursive, missing paths are ignored, and mount points are crossed.
+#[derive(Clone, Debug)]
+pub struct RemoveOptions {
+    recursive: bool,
+    missing_ok: bool,
+    mounts: Mounts,
+    policy: Option<PermitPolicy>,
+}
+
+impl Default for RemoveOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RemoveOptions {
+    /// # Creates the default removal options.
+    pub const fn new() -> Self {
+        Self {
+            recursive: false,
+            missing_ok: true,
+            mounts: Mounts::Cross,
+            policy: None,
+        }
+    }
+
+    /// # Whether to remove directories recursively.
+    /// Recursive removal refuses to remove protected paths; see `protect()`.
+    pub const fn recursive(mut self, yes: bool) -> Self {
+        self.recursive = yes;
+        self
+    }
+
+    /// # Whether to ignore attempts to remove missing paths.
+    pub const fn missing_ok(mut self, yes: bool) -> Self {
+        self.missing_ok = yes;
+        self
+    }
+
+    /// # What recursive removal does at mount points.
+    /// `Mounts::Refuse` checks for mount points before removing anything.
+    pub const fn mounts(mut self, mounts: Mounts) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    /// # Which errors to ignore, instead of the installed policy.
+    pub fn policy(mut self, policy: PermitPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// # Removes a directory with these options.
+    /// See `rmdir()` and `rmdir_r()`. Returns `SkippedNotEmpty` if a skipped mount point kept `dir`
+    /// around.
+    pub fn rmdir<P>(&self, dir: P) -> Result<RemoveOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let op = if self.recursive {
+            Op::RmdirR
+        } else {
+            Op::Rmdir
+        };
+        if self.recursive {
+            guard::check(op, dir)?;
+        }
+
+        let outcome = self.remove_dir(op, dir)?;
+        self.check_missing(op, dir, outcome)
+    }
+
+    /// # Removes a file or symlink with these options.
+    /// See `rmf()`.
+    pub fn rmf<P>(&self, file: P) -> Result<RemoveOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        let outcome = remove_file_or_link(file, self.policy.as_ref())?;
+        self.check_missing(Op::Rmf, file, outcome)
+    }
+
+    /// # Removes a path with these options.
+    /// See `rm()` and `rmr()`.
+    pub fn rm<P>(&self, path: P) -> Result<RemoveOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let op = if self.recursive { Op::Rmr } else { Op::Rm };
+        if self.recursive {
+            guard::check(op, path)?;
+        }
+
+        let outcome = dispatch(op, path, &|p| self.remove_dir(op, p), &|p| {
+            remove_file_or_link(p, self.policy.as_ref())
+        })?;
+        self.check_missing(op, path, outcome)
+    }
+
+    /// Removes a directory, recursively if configured, without checking whether it is protected
+    fn remove_dir(&self, op: Op, dir: &Path) -> Result<RemoveOutcome> {
+        use RemoveOutcome::*;
+        if dry::active() {
+            return dry::remove_dir(op, dir, self.recursive, self.mounts);
+        }
+        if !self.recursive {
+            return iopermit!(
+                self.policy.as_ref();
+                remove_dir(dir) => Removed,
+                NotFound => WasMissing,
+                DirectoryNotEmpty => SkippedNotEmpty,
+            )
+            .ctx(op, dir);
+        }
+
+        let e = match remove_tree(op, dir, self.mounts) {
+            Err(e) => e,
+            r => return r,
+        };
+        let missing = e.kind() == io::ErrorKind::NotFound;
+        if !policy::permits(self.policy.as_ref(), e.io(), missing) {
+            return Err(e);
+        }
+        tracing::debug!("Permitting {:?} for {dir:?}", e.kind());
+        Ok(if missing {
+            WasMissing
+        } else {
+            Ignored(e.kind())
+        })
+    }
+
+    /// Errors if `path` was missing and that isn't ok
+    fn check_missing(&self, op: Op, path: &Path, outcome: RemoveOutcome) -> Result<RemoveOutcome> {
+        if outcome == RemoveOutcome::WasMissing && !self.missing_ok {
+            return Err(Error::new(op, path, io::ErrorKind::NotFound.into()));
+        }
+        Ok(outcome)
+    }
+}
+
+/// Removes a file or symlink, ignoring missing ones
+fn remove_file_or_link(file: &Path, policy: Option<&PermitPolicy>) -> Result<RemoveOutcome> {
+    use RemoveOutcome::*;
+    if dry::active() {
+        return dry::remove_file(file);
+    }
+    iopermit!(policy; remove_file(file) => Removed, NotFound => WasMissing).ctx(Op::Rmf, file)
 }
 
 /// # Removes a directory
 /// Ignores attempts to remove missing or populated directories.
-pub fn rmdir<P>(dir: P) -> io::Result<()>
+pub fn rmdir<P>(dir: P) -> Result<RemoveOutcome>
 where
     P: AsRef<Path>,
 {
-    iopermit!(remove_dir(dir), NotFound, DirectoryNotEmpty)
+    RemoveOptions::new().rmdir(dir)
 }
 
 /// # Removes a directory recursively
-/// Ignores attempts to remove missing directories.
-pub fn rmdir_r<P>(dir: P) -> io::Result<()>
+/// Ignores attempts to remove missing directories. Never follows symlinks, even ones swapped in
+/// while the removal is underway, and handles trees deeper than `PATH_MAX`. Refuses to remove
+/// protected paths; see `protect()`.
+pub fn rmdir_r<P>(dir: P) -> Result<RemoveOutcome>
+where
+    P: AsRef<Path>,
+{
+    RemoveOptions::new().recursive(true).rmdir(dir)
+}
+
+/// # Removes a directory and its empty parents, up to a stop point.
+/// Like `rmdir --parents`, but never removes `stop_at` or anything above it. Missing directories
+/// are passed over, and the first populated one ends the pruning quietly, as with `rmdir()`. Both
+/// paths are made absolute and compared lexically, so `dir` must be under `stop_at` and can't
+/// contain `..`. Returns the directories removed, innermost first.
+pub fn rmdir_p<P, Q>(dir: P, stop_at: Q) -> Result<Vec<PathBuf>>
 where
     P: AsRef<Path>,
+    Q: AsRef<Path>,
 {
-    iopermit!(remove_dir_all(dir), NotFound)
+    let (dir, stop_at) = bounded(Op::RmdirP, dir.as_ref(), stop_at.as_ref())?;
+    prune(&dir, &stop_at)
+}
+
+/// # Removes a file and its empty parents, up to a stop point.
+/// Removes `file` like `rmf()`, then prunes its parents like `rmdir_p()`. Returns the outcome for
+/// `file` and the directories removed, innermost first.
+pub fn rmf_prune<P, Q>(file: P, stop_at: Q) -> Result<(RemoveOutcome, Vec<PathBuf>)>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (file, stop_at) = bounded(Op::RmfPrune, file.as_ref(), stop_at.as_ref())?;
+    let outcome = rmf(&file)?;
+    let parent = file.parent().unwrap_or(&stop_at);
+    Ok((outcome, prune(parent, &stop_at)?))
+}
+
+/// Makes `path` and `stop_at` absolute, erroring unless `path` is lexically under `stop_at`
+fn bounded(op: Op, path: &Path, stop_at: &Path) -> Result<(PathBuf, PathBuf)> {
+    let abs = std::path::absolute(path).ctx(op, path)?;
+    let stop = std::path::absolute(stop_at).ctx(op, stop_at)?;
+    let e = if abs.components().any(|c| c == Component::ParentDir) {
+        "refusing to prune a path containing '..'"
+    } else if abs == stop || !abs.starts_with(&stop) {
+        "is not under the stop point"
+    } else {
+        return Ok((abs, stop));
+    };
+    Err(Error::new(
+        op,
+        path,
+        io::Error::new(io::ErrorKind::InvalidInput, e),
+    ))
+}
+
+/// Removes `dir` and its parents below `stop_at` until one is populated
+fn prune(dir: &Path, stop_at: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for d in dir.ancestors().take_while(|d| *d != stop_at) {
+        match rmdir(d)? {
+            RemoveOutcome::Removed => removed.push(d.to_path_buf()),
+            RemoveOutcome::WasMissing => (),
+            RemoveOutcome::SkippedNotEmpty | RemoveOutcome::Ignored(_) => break,
+        }
+    }
+    Ok(removed)
 }
 
 /// # Removes a file or symlink.
 /// Ignores attempts to remove missing files.
-pub fn rmf<P>(file: P) -> io::Result<()>
+pub fn rmf<P>(file: P) -> Result<RemoveOutcome>
 where
     P: AsRef<Path>,
 {
-    iopermit!(remove_file(file), NotFound)
+    RemoveOptions::new().rmf(file)
 }
 
 /// # Removes a path.
-/// Removes a symlink, file, or directory, deciding which internally.
-pub fn rm<P>(path: P) -> io::Result<()>
+/// Removes a symlink, file, directory, or special file, deciding which internally.
+pub fn rm<P>(path: P) -> Result<RemoveOutcome>
 where
     P: AsRef<Path>,
 {
-    let p = path.as_ref();
-    if p.is_symlink() || p.is_file() {
-        rmf(path)
-    } else {
-        rmdir(path)
-    }
+    RemoveOptions::new().rm(path)
 }
 
 /// # Removes a path, recursively if needed.
-/// Removes a symlink, file, or directory, deciding which internally.
-/// The only difference between `rm()` and this is recursion.
-pub fn rmr<P>(path: P) -> io::Result<()>
+/// Removes a symlink, file, directory, or special file, deciding which internally.
+/// The only difference between `rm()` and this is recursion. Refuses to remove protected paths;
+/// see `protect()`.
+pub fn rmr<P>(path: P) -> Result<RemoveOutcome>
 where
     P: AsRef<Path>,
 {
-    let p = path.as_ref();
-    if p.is_symlink() || p.is_file() {
-        rmf(path)
+    RemoveOptions::new().recursive(true).rm(path)
+}
+
+/// Removes `path` with `rmd` if it's a directory, or with `rmf` otherwise
+///
+/// The path is only stat'd once and symlinks are not followed. If the entry changes type between
+/// the stat and the removal, the other strategy is tried once.
+fn dispatch(
+    op: Op,
+    path: &Path,
+    rmd: &dyn Fn(&Path) -> Result<RemoveOutcome>,
+    rmf: &dyn Fn(&Path) -> Result<RemoveOutcome>,
+) -> Result<RemoveOutcome> {
+    let is_dir = match kind(path) {
+        Ok(Some(k)) => k == FileKind::Dir,
+        Ok(None) => return Ok(RemoveOutcome::WasMissing),
+        Err(e) => return Err(Error::new(op, path, e.into_io())),
+    };
+
+    let (first, second, changed) = if is_dir {
+        (rmd, rmf, io::ErrorKind::NotADirectory)
     } else {
-        rmdir_r(path)
+        (rmf, rmd, io::ErrorKind::IsADirectory)
+    };
+
+    match first(path) {
+        Err(e) if e.kind() == changed => {
+            tracing::debug!("{path:?} changed type during removal, retrying");
+            second(path)
+        }
+        r => r,
     }
 }
 
 /// # Check whether a path is a directory.
-/// Follows symlinks.
-pub fn is_dir<P>(path: P) -> io::Result<bool>
+/// Follows symlinks, resolving relative targets against the link's parent. Missing paths and
+/// dangling symlinks are not directories.
+pub fn is_dir<P>(path: P) -> Result<bool>
 where
     P: AsRef<Path>,
 {
     let p = path.as_ref();
-    Ok(p.is_dir() || (p.is_symlink() && read_link(path)?.is_dir()))
+    match kind(p) {
+        Ok(k) => Ok(k.is_some_and(FileKind::is_dir)),
+        Err(e) => Err(Error::new(Op::IsDir, p, e.into_io())),
+    }
 }
 
 #[cfg(test)]
@@ -141,37 +1052,195 @@ mod test {
 
     #[test]
     fn mkf_with_parents() {
-        let f = Path::new("/tmp/fshelpers/path/to/test/file");
+        let t = tempdir().unwrap();
+        let f = &t.path().join("path/to/test/file");
         assert!(mkf_p(f).is_ok() && f.exists())
     }
 
     #[test]
     fn rm_file_ignore_missing() {
-        let f = Path::new("/tmp/fshelpers/path/to/test/nonexistent");
+        let t = tempdir().unwrap();
+        let f = &t.path().join("path/to/test/nonexistent");
         assert!(rm(f).is_ok() && !f.exists())
     }
 
     #[test]
     fn rmdir_ignore_populated() {
-        let d = Path::new("/tmp/fshelpers/path/to/test");
+        let t = tempdir().unwrap();
+        let d = &t.path().join("path/to/test");
         mkf_p(d.join("hello")).unwrap();
         assert!(rmdir(d).is_ok() && d.exists())
     }
 
     #[test]
     fn create_and_remove_dir() {
-        assert!(mkdir("hello").is_ok() && rmdir("hello").is_ok())
+        let t = tempdir().unwrap();
+        let d = &t.path().join("hello");
+        assert!(mkdir(d).is_ok() && rmdir(d).is_ok() && !d.exists())
     }
 
     #[test]
     fn create_dir_with_parents_and_ignore_remove() {
-        let d = Path::new("hi/hello");
-        assert!(mkdir_p("hi/hello").is_ok() && rmdir("hello").is_ok() && d.exists())
+        let t = tempdir().unwrap();
+        let d = &t.path().join("hi/hello");
+        assert!(mkdir_p(d).is_ok() && rmdir(t.path().join("hi")).is_ok() && d.exists())
+    }
+
+    #[test]
+    fn outcomes_report_changes() {
+        let t = tempdir().unwrap();
+        let d = &t.path().join("outcome");
+        assert_eq!(mkdir(d).unwrap(), CreateOutcome::Created);
+        assert_eq!(mkdir(d).unwrap(), CreateOutcome::AlreadyExisted);
+        assert_eq!(mkf(d.join("f")).unwrap(), CreateOutcome::Created);
+        assert_eq!(rmdir(d).unwrap(), RemoveOutcome::SkippedNotEmpty);
+        assert_eq!(rmf(d.join("f")).unwrap(), RemoveOutcome::Removed);
+        assert_eq!(rmf(d.join("f")).unwrap(), RemoveOutcome::WasMissing);
+        assert_eq!(rmdir(d).unwrap(), RemoveOutcome::Removed);
+    }
+
+    #[test]
+    fn exists_checks_kind() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkf_p(d.join("file")).unwrap();
+        mkdir(d.join("dir")).unwrap();
+        assert_eq!(
+            mkdir(d.join("file")).unwrap_err().kind(),
+            io::ErrorKind::NotADirectory
+        );
+        assert_eq!(
+            mkf(d.join("dir")).unwrap_err().kind(),
+            io::ErrorKind::IsADirectory
+        );
+
+        let lax = MkdirOptions::new().strict_exists(false);
+        assert!(lax.mkdir(d.join("file")).is_ok());
+        assert!(
+            MkfOptions::new()
+                .strict_exists(false)
+                .mkf(d.join("dir"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn rm_special_files() {
+        let t = tempdir().unwrap();
+        let d = &t.path().join("special");
+        mkdir(d).unwrap();
+        let sock = d.join("sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&sock).unwrap();
+        assert_eq!(rm(&sock).unwrap(), RemoveOutcome::Removed);
+        assert_eq!(rm(&sock).unwrap(), RemoveOutcome::WasMissing);
+        assert_eq!(rm(d).unwrap(), RemoveOutcome::Removed);
+    }
+
+    #[test]
+    fn create_with_exact_modes() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        let mode = |p: &Path| p.metadata().unwrap().permissions().mode() & 0o7777;
+
+        let opts = MkdirOptions::new()
+            .parents(true)
+            .mode(0o777)
+            .parent_mode(0o700);
+        assert!(opts.mkdir(d.join("a/b")).unwrap().created());
+        assert_eq!((mode(&d.join("a")), mode(&d.join("a/b"))), (0o700, 0o777));
+
+        assert!(mkf_mode(d.join("f"), 0o666).unwrap().created());
+        assert_eq!(mode(&d.join("f")), 0o666);
+
+        // Existing entries are only changed when enforcing
+        mkdir_mode(d.join("a/b"), 0o750).unwrap();
+        assert_eq!(mode(&d.join("a/b")), 0o777);
+        MkdirOptions::new()
+            .mode(0o750)
+            .enforce_mode(true)
+            .mkdir(d.join("a/b"))
+            .unwrap();
+        assert_eq!(mode(&d.join("a/b")), 0o750);
+    }
+
+    #[test]
+    fn options_toggle_permits() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkdir_p(d.join("dir")).unwrap();
+        std::os::unix::fs::symlink("dir", d.join("link")).unwrap();
+
+        let strict = MkdirOptions::new().exist_ok(false);
+        assert_eq!(
+            strict.mkdir(d.join("dir")).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(mkdir(d.join("link")).is_ok());
+        assert!(
+            MkdirOptions::new()
+                .follow_symlinks(false)
+                .mkdir(d.join("link"))
+                .is_err()
+        );
+
+        let strict = RemoveOptions::new().missing_ok(false);
+        assert_eq!(
+            strict.rmf(d.join("missing")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(
+            RemoveOptions::new()
+                .recursive(true)
+                .rm(d)
+                .unwrap()
+                .removed()
+        );
+    }
+
+    #[test]
+    fn list_created_dirs() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkdir(d.join("a")).unwrap();
+
+        let created = mkdir_p_created(d.join("a/b/c")).unwrap();
+        assert_eq!(created, [d.join("a/b"), d.join("a/b/c")]);
+        assert!(mkdir_p_created(d.join("a/b/c")).unwrap().is_empty());
+
+        let (outcome, created) = mkf_p_created(d.join("x/y/f")).unwrap();
+        assert!(outcome.created());
+        assert_eq!(created, [d.join("x"), d.join("x/y")]);
+
+        // NOTE: Parents created before a failure are removed
+        assert!(mkf_p_created(d.join("n/m").join("f".repeat(300))).is_err());
+        assert!(!d.join("n").exists());
+    }
+
+    #[test]
+    fn prune_empty_parents() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkf_p(d.join("a/b/c/file")).unwrap();
+        mkf(d.join("a/keep")).unwrap();
+
+        let (outcome, removed) = rmf_prune(d.join("a/b/c/file"), d).unwrap();
+        assert!(outcome.removed());
+        assert_eq!(removed, [d.join("a/b/c"), d.join("a/b")]);
+        assert!(d.join("a/keep").exists());
+
+        mkdir_p(d.join("x/y")).unwrap();
+        assert_eq!(rmdir_p(d.join("x/y"), d).unwrap().len(), 2);
+        assert!(d.exists());
+        assert!(rmdir_p(d.join("a/../a"), d).is_err());
+        assert!(rmdir_p(d, d.join("a")).is_err());
     }
 
     #[test]
     fn rm_recursive() {
-        assert!(rmdir_r("/tmp/fshelpers").is_ok());
-        assert!(rmdir_r("hi").is_ok());
+        let t = tempdir().unwrap();
+        let d = &t.path().join("fshelpers");
+        mkf_p(d.join("path/to/test/file")).unwrap();
+        mkdir_p(d.join("hi/hello")).unwrap();
+        assert!(rmdir_r(d).is_ok() && !d.exists());
     }
 }
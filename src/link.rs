@@ -0,0 +1,232 @@
+use std::{
+    collections::VecDeque,
+    env,
+    ffi::OsString,
+    fs::{read_link, rename, symlink_metadata},
+    io,
+    os::unix::fs::symlink,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{Action, Context, Error, Op, Result, dry, mkparent, rmf, tmp_sibling};
+
+/// # The most symlinks `resolve()` and `resolve_nonexistent()` follow.
+/// Matches the kernel's limit. Resolving more errors as a symlink loop.
+pub const MAX_HOPS: usize = 40;
+
+/// # Creates a symlink.
+/// Ignores attempts to create a link that already points to `target`. Errors if `link` exists and
+/// is anything else.
+pub fn ln_s<P, Q>(target: P, link: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (target, link) = (target.as_ref(), link.as_ref());
+    let made = if dry::active() {
+        pretend_symlink(target, link)
+    } else {
+        symlink(target, link)
+    };
+    match made {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if read_link(link).is_ok_and(|t| t == target) {
+                tracing::debug!("Permitting {:?} for {link:?} -> {target:?}", e.kind());
+                Ok(())
+            } else {
+                Err(e).ctx(Op::LnS, link)
+            }
+        }
+        r => r.ctx(Op::LnS, link),
+    }
+}
+
+/// # Creates a symlink, with parents.
+/// Behaves like `ln_s()` otherwise.
+pub fn ln_s_p<P, Q>(target: P, link: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    mkparent(link.as_ref())?;
+    ln_s(target, link)
+}
+
+/// # Creates a symlink, replacing whatever is in the way.
+/// Existing symlinks and files are atomically replaced. Refuses to replace directories.
+pub fn ln_sf<P, Q>(target: P, link: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (target, link) = (target.as_ref(), link.as_ref());
+    if dry::active() {
+        if symlink_metadata(link).is_ok_and(|m| m.is_dir()) {
+            return Err(Error::new(
+                Op::LnSf,
+                link,
+                io::ErrorKind::IsADirectory.into(),
+            ));
+        }
+        dry::record(Action::Symlink(link.to_path_buf(), target.to_path_buf()));
+        return Ok(());
+    }
+
+    let (tmp, ()) = tmp_sibling(link, |tmp| symlink(target, tmp)).ctx(Op::LnSf, link)?;
+    rename(&tmp, link)
+        .inspect_err(|_| {
+            let _ = rmf(&tmp);
+        })
+        .ctx(Op::LnSf, link)
+}
+
+/// Pretends to create a symlink, failing like `symlink()` would if something is in the way
+fn pretend_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    match symlink_metadata(link) {
+        Ok(_) => Err(io::ErrorKind::AlreadyExists.into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            dry::record(Action::Symlink(link.to_path_buf(), target.to_path_buf()));
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// # Resolves a path to an absolute path with no symlinks, `.`, or `..`.
+/// Every component but the last must exist, and an empty path is `NotFound`. Roughly corresponds
+/// to `readlink -f`.
+pub fn resolve<P>(path: P) -> Result<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    walk(path, false).ctx(Op::Resolve, path)
+}
+
+/// # Resolves a path to an absolute path with no symlinks, `.`, or `..`.
+/// No component needs to exist, but an empty path is still `NotFound`. Roughly corresponds to
+/// `readlink -m`.
+pub fn resolve_nonexistent<P>(path: P) -> Result<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    walk(path, true).ctx(Op::ResolveNonexistent, path)
+}
+
+/// Resolves `path` one component at a time, splicing in symlink targets as they're found
+fn walk(path: &Path, missing_ok: bool) -> io::Result<PathBuf> {
+    if path.as_os_str().is_empty() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    let mut queue = components(path);
+    let mut resolved = if path.is_absolute() {
+        PathBuf::from("/")
+    } else {
+        env::current_dir()?
+    };
+    let mut hops = 0;
+
+    while let Some(c) = queue.pop_front() {
+        match c.as_os_str().to_str() {
+            Some("/") => resolved = PathBuf::from("/"),
+            Some(".") => {}
+            Some("..") => {
+                resolved.pop();
+            }
+            _ => {
+                resolved.push(&c);
+                let meta = match symlink_metadata(&resolved) {
+                    // NOTE: Components under a file can't exist either, which only matters to -m
+                    Err(e) if missing_ok && e.kind() == io::ErrorKind::NotADirectory => continue,
+                    Err(e)
+                        if e.kind() == io::ErrorKind::NotFound
+                            && (missing_ok || queue.is_empty()) =>
+                    {
+                        continue;
+                    }
+                    r => r?,
+                };
+                if !meta.is_symlink() {
+                    continue;
+                }
+
+                hops += 1;
+                if hops > MAX_HOPS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = read_link(&resolved)?;
+                resolved.pop();
+                for t in components(&target).into_iter().rev() {
+                    queue.push_front(t);
+                }
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .map(|c| match c {
+            Component::Prefix(p) => p.as_os_str().to_owned(),
+            Component::RootDir => "/".into(),
+            Component::CurDir => ".".into(),
+            Component::ParentDir => "..".into(),
+            Component::Normal(n) => n.to_owned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{is_dir, mkf_p, tempdir};
+
+    #[test]
+    fn ln_s_is_idempotent() {
+        let t = tempdir().unwrap();
+        let link = &t.path().join("idempotent/link");
+        assert!(ln_s_p("target", link).is_ok());
+        assert!(ln_s("target", link).is_ok());
+        assert!(ln_s("other", link).is_err());
+    }
+
+    #[test]
+    fn ln_sf_replaces_files() {
+        let t = tempdir().unwrap();
+        let link = &t.path().join("force/link");
+        mkf_p(link).unwrap();
+        assert!(ln_sf("target", link).is_ok());
+        assert_eq!(read_link(link).unwrap(), Path::new("target"));
+    }
+
+    #[test]
+    fn resolve_relative_links() {
+        let t = tempdir().unwrap();
+        let d = &t.path().join("resolve");
+        mkf_p(d.join("real/file")).unwrap();
+        ln_s("real", d.join("rel")).unwrap();
+        ln_s("rel/file", d.join("chain")).unwrap();
+        ln_s("loop", d.join("loop")).unwrap();
+
+        assert_eq!(resolve(d.join("chain")).unwrap(), d.join("real/file"));
+        assert_eq!(
+            resolve(d.join("rel/missing")).unwrap(),
+            d.join("real/missing")
+        );
+        assert!(resolve(d.join("rel/missing/deeper")).is_err());
+        assert_eq!(
+            resolve_nonexistent(d.join("rel/missing/../deeper")).unwrap(),
+            d.join("real/deeper")
+        );
+        assert!(resolve(d.join("loop")).is_err());
+        for r in [resolve(""), resolve_nonexistent("")] {
+            assert_eq!(r.unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+
+        ln_s("missing", d.join("dangling")).unwrap();
+        assert!(is_dir(d.join("rel")).unwrap() && !is_dir(d.join("dangling")).unwrap());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{metadata, read},
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{Context, Op, Result};
+
+/// # What recursive helpers do at mount points.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Mounts {
+    /// Cross into other filesystems
+    #[default]
+    Cross,
+    /// Leave mount points and everything under them alone. Roughly corresponds to
+    /// `--one-file-system`.
+    Skip,
+    /// Error instead of crossing into another filesystem
+    Refuse,
+}
+
+/// # Lists the mount points under a path.
+/// Parses `/proc/self/mountinfo`. `path` itself is not included, even if it is a mount point.
+pub fn mount_points<P>(path: P) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let root = path.canonicalize().ctx(Op::MountPoints, path)?;
+    let info = read("/proc/self/mountinfo").ctx(Op::MountPoints, path)?;
+
+    let mut mounts = info
+        .split(|&b| b == b'\n')
+        .filter_map(|line| line.split(|&b| b == b' ').nth(4))
+        .map(unescape)
+        .filter(|m| m.starts_with(&root) && *m != root)
+        .collect::<Vec<_>>();
+    mounts.sort();
+    mounts.dedup();
+    Ok(mounts)
+}
+
+/// Decodes the octal escapes mountinfo uses for spaces, tabs, newlines, and backslashes
+fn unescape(field: &[u8]) -> PathBuf {
+    let mut out = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let oct = field
+            .get(i + 1..i + 4)
+            .filter(|o| field[i] == b'\\' && o.iter().all(|b| (b'0'..=b'7').contains(b)));
+        match oct {
+            Some(o) => {
+                out.push(o.iter().fold(0u8, |n, b| n.wrapping_mul(8) + (b - b'0')));
+                i += 4;
+            }
+            None => {
+                out.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsStr::from_bytes(&out))
+}
+
+/// The filesystem a walk started on
+///
+/// Directories on another device, or which are mount points (including bind mounts from the same
+/// filesystem), are outside it.
+pub(crate) struct Boundary {
+    dev: u64,
+    mounts: HashSet<(u64, u64)>,
+}
+
+impl Boundary {
+    pub(crate) fn new(root: &Path) -> Result<Self> {
+        let dev = metadata(root).ctx(Op::MountPoints, root)?.dev();
+        let mounts = mount_points(root)?
+            .iter()
+            .filter_map(|m| metadata(m).ok())
+            .map(|m| (m.dev(), m.ino()))
+            .collect();
+        Ok(Self { dev, mounts })
+    }
+
+    /// Whether there are no mount points under the root
+    pub(crate) fn is_clear(&self) -> bool {
+        self.mounts.is_empty()
+    }
+
+    /// Whether a directory with this device and inode is outside the boundary
+    pub(crate) fn excludes(&self, dev: u64, ino: u64) -> bool {
+        dev != self.dev || self.mounts.contains(&(dev, ino))
+    }
+}
+
+/// The error for refusing to cross a mount point
+pub(crate) fn crossing() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::CrossesDevices,
+        "refusing to cross a mount point",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CopyOptions, RemoveOptions, RemoveOutcome, mkf_p, tempdir};
+
+    #[test]
+    fn mount_points_under_root() {
+        let mounts = mount_points("/").unwrap();
+        assert!(
+            mounts
+                .iter()
+                .all(|m| m.is_absolute() && m != Path::new("/"))
+        );
+        assert!(mounts.contains(&PathBuf::from("/proc")));
+    }
+
+    #[test]
+    fn refuse_before_crossing() {
+        let t = tempdir().unwrap();
+        let dst = &t.path().join("dev");
+        if mount_points("/dev").unwrap().is_empty() {
+            return;
+        }
+
+        let e = CopyOptions::new()
+            .mounts(Mounts::Refuse)
+            .cp_r("/dev", dst)
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::CrossesDevices);
+        assert!(!dst.exists());
+    }
+
+    #[test]
+    fn skip_without_mounts() {
+        let t = tempdir().unwrap();
+        let d = &t.path().join("plain");
+        mkf_p(d.join("a/b")).unwrap();
+        let opts = RemoveOptions::new().recursive(true).mounts(Mounts::Skip);
+        assert_eq!(opts.rmdir(d).unwrap(), RemoveOutcome::Removed);
+    }
+
+    #[test]
+    fn unescape_octal() {
+        assert_eq!(unescape(br"/mnt/a\040b\134"), PathBuf::from(r"/mnt/a b\"));
+    }
+}
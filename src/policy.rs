@@ -0,0 +1,222 @@
+use std::{cell::RefCell, fmt, io, marker::PhantomData, sync::Arc};
+
+use permitit::Permit;
+
+use crate::{CreateOutcome, RemoveOutcome};
+
+type Predicate = Arc<dyn Fn(&io::Error) -> bool + Send + Sync>;
+
+/// # Which errors helpers ignore.
+/// Each helper ignores some errors by default, like `rmdir()` ignoring `DirectoryNotEmpty`. A
+/// policy can stop ignoring those, or ignore more. Errors ignored only because of a policy are
+/// reported as an `Ignored` outcome.
+///
+/// Pass a policy to an options builder, or `install()` it for the current thread.
+#[derive(Clone, Default)]
+pub struct PermitPolicy {
+    permitted: Vec<io::ErrorKind>,
+    denied: Vec<io::ErrorKind>,
+    predicates: Vec<Predicate>,
+}
+
+impl fmt::Debug for PermitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermitPolicy")
+            .field("permitted", &self.permitted)
+            .field("denied", &self.denied)
+            .field("predicates", &self.predicates.len())
+            .finish()
+    }
+}
+
+impl PermitPolicy {
+    /// # Creates a policy that keeps each helper's defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # Also ignores errors of this kind.
+    pub fn permit(mut self, kind: io::ErrorKind) -> Self {
+        self.denied.retain(|k| *k != kind);
+        self.permitted.push(kind);
+        self
+    }
+
+    /// # Stops ignoring errors of this kind, even where a helper would by default.
+    pub fn deny(mut self, kind: io::ErrorKind) -> Self {
+        self.permitted.retain(|k| *k != kind);
+        self.denied.push(kind);
+        self
+    }
+
+    /// # Also ignores errors matching a predicate.
+    /// Errors matching any of the predicates are ignored. Denied kinds are never ignored, even if a
+    /// predicate matches.
+    pub fn permit_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error) -> bool + Send + Sync + 'static,
+    {
+        self.predicates.push(Arc::new(f));
+        self
+    }
+
+    /// # Installs this policy for the current thread.
+    /// Applies to helpers not given a policy explicitly until the guard is dropped. Installing
+    /// another policy shadows this one. Dropping the guard also uninstalls any policies installed
+    /// after it.
+    pub fn install(self) -> PolicyGuard {
+        let depth = SCOPED.with_borrow_mut(|s| {
+            s.push(self);
+            s.len() - 1
+        });
+        PolicyGuard(depth, PhantomData)
+    }
+
+    /// Whether `e` is ignored, given whether the helper ignores it by default
+    fn permits(&self, e: &io::Error, default: bool) -> bool {
+        let kind = e.kind();
+        if self.denied.contains(&kind) {
+            return false;
+        }
+        default || self.permitted.contains(&kind) || self.predicates.iter().any(|f| f(e))
+    }
+}
+
+thread_local! {
+    static SCOPED: RefCell<Vec<PermitPolicy>> = const { RefCell::new(Vec::new()) };
+}
+
+/// # Uninstalls a policy when dropped.
+/// Returned by `PermitPolicy::install()`.
+#[must_use = "the policy is uninstalled when the guard is dropped"]
+pub struct PolicyGuard(usize, PhantomData<*const ()>);
+
+impl Drop for PolicyGuard {
+    fn drop(&mut self) {
+        SCOPED.with_borrow_mut(|s| s.truncate(self.0));
+    }
+}
+
+/// Whether `e` is ignored under `policy`, or the installed policy if there isn't one
+pub(crate) fn permits(policy: Option<&PermitPolicy>, e: &io::Error, default: bool) -> bool {
+    match policy {
+        Some(p) => p.permits(e, default),
+        None => SCOPED.with_borrow(|s| s.last().map_or(default, |p| p.permits(e, default))),
+    }
+}
+
+/// An outcome that can report an error ignored because of a policy
+pub(crate) trait Ignorable: Copy {
+    fn ignored(kind: io::ErrorKind) -> Self;
+}
+
+impl Ignorable for CreateOutcome {
+    fn ignored(kind: io::ErrorKind) -> Self {
+        Self::Ignored(kind)
+    }
+}
+
+impl Ignorable for RemoveOutcome {
+    fn ignored(kind: io::ErrorKind) -> Self {
+        Self::Ignored(kind)
+    }
+}
+
+/// Maps `result` to an outcome, ignoring errors the policy permits
+///
+/// `defaults` pairs the kinds a helper ignores by default with the outcomes they mean.
+pub(crate) fn permit<T>(
+    policy: Option<&PermitPolicy>,
+    result: io::Result<()>,
+    ok: T,
+    defaults: &[(io::ErrorKind, T)],
+    what: &str,
+) -> io::Result<T>
+where
+    T: Ignorable,
+{
+    let mut outcome = ok;
+    result.permit(|e| {
+        let default = defaults
+            .iter()
+            .find(|(k, _)| *k == e.kind())
+            .map(|(_, o)| *o);
+        let permitted = permits(policy, e, default.is_some());
+        if permitted {
+            tracing::debug!("Permitting {:?} for {what:?}", e.kind());
+            outcome = default.unwrap_or_else(|| T::ignored(e.kind()));
+        }
+        permitted
+    })?;
+    Ok(outcome)
+}
+
+/// Backs `permit!()`
+pub fn permit_kinds<T>(
+    result: io::Result<T>,
+    kinds: &[io::ErrorKind],
+    what: &str,
+) -> io::Result<Option<T>> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(e)
+            .permit(|e: &io::Error| {
+                let permitted = kinds.contains(&e.kind());
+                if permitted {
+                    tracing::debug!("Permitting {:?} for {what:?}", e.kind());
+                }
+                permitted
+            })
+            .map(|()| None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MkdirOptions, mkdir, mkf_p, rmdir, tempdir};
+
+    #[test]
+    fn deny_and_permit_kinds() {
+        let t = tempdir().unwrap();
+        let d = &t.path().join("explicit");
+        mkf_p(d.join("file")).unwrap();
+
+        let policy = PermitPolicy::new().permit(io::ErrorKind::NotADirectory);
+        let opts = MkdirOptions::new().policy(policy);
+        assert_eq!(
+            opts.mkdir(d.join("file/sub")).unwrap(),
+            CreateOutcome::Ignored(io::ErrorKind::NotADirectory)
+        );
+
+        {
+            let _guard = PermitPolicy::new()
+                .deny(io::ErrorKind::DirectoryNotEmpty)
+                .install();
+            assert_eq!(
+                rmdir(d).unwrap_err().kind(),
+                io::ErrorKind::DirectoryNotEmpty
+            );
+            assert!(mkdir(d.join("file/sub")).is_err());
+        }
+        assert_eq!(rmdir(d).unwrap(), RemoveOutcome::SkippedNotEmpty);
+    }
+
+    #[test]
+    fn predicates_combine_and_guards_unwind() {
+        let kind = |k| move |e: &io::Error| e.kind() == k;
+        let policy = PermitPolicy::new()
+            .permit_if(kind(io::ErrorKind::NotFound))
+            .permit_if(kind(io::ErrorKind::PermissionDenied));
+        assert!(policy.permits(&io::ErrorKind::NotFound.into(), false));
+        assert!(policy.permits(&io::ErrorKind::PermissionDenied.into(), false));
+
+        let installed = || SCOPED.with_borrow(Vec::len);
+        let outer = PermitPolicy::new().install();
+        let inner = policy.install();
+        drop(outer);
+        assert_eq!(installed(), 0);
+        drop(inner);
+        assert_eq!(installed(), 0);
+    }
+}
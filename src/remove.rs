@@ -0,0 +1,289 @@
+use std::{
+    ffi::{CStr, CString, OsStr},
+    io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+use crate::{
+    Context, Error, Mounts, Op, RemoveOutcome, Result,
+    mounts::{Boundary, crossing},
+};
+
+/// A directory being emptied
+struct Frame {
+    name: CString,
+    dev: libc::dev_t,
+    ino: libc::ino_t,
+    entries: Vec<CString>,
+    /// Whether a mount point under this directory was left alone
+    skipped: bool,
+}
+
+/// Removes a tree without following symlinks, relative to directory file descriptors
+///
+/// Every entry is opened with `O_NOFOLLOW` relative to its parent's descriptor, so swapping a
+/// directory for a symlink mid-walk can't redirect removal outside the tree. Only two descriptors
+/// are held at a time; the walk climbs back up through `..`, verifying each parent is the directory
+/// it descended from, so depth is bounded by neither `PATH_MAX` nor the descriptor limit.
+///
+/// A symlink at `path` is removed itself. Errors with `NotADirectory` for any other non-directory.
+/// When mount points are skipped, the directories containing them are left behind.
+pub(crate) fn remove_tree(op: Op, path: &Path, mounts: Mounts) -> Result<RemoveOutcome> {
+    use RemoveOutcome::*;
+
+    let Some(name) = path.file_name() else {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "refusing to remove '.' or '..'",
+        );
+        return Err(Error::new(op, path, e));
+    };
+    let name = cstr(name).ctx(op, path)?;
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let parent = cstr(parent.as_os_str())
+        .and_then(|p| open(libc::AT_FDCWD, &p, 0))
+        .ctx(op, path)?;
+
+    let st = match lstatat(parent.as_raw_fd(), &name) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(WasMissing),
+        r => r.ctx(op, path)?,
+    };
+    if !is_dir(&st) {
+        if st.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            return unlinkat(parent.as_raw_fd(), &name, 0)
+                .ctx(op, path)
+                .map(|()| Removed);
+        }
+        return Err(Error::new(op, path, io::ErrorKind::NotADirectory.into()));
+    }
+
+    let boundary = match mounts {
+        Mounts::Cross => None,
+        _ => Some(Boundary::new(path)?),
+    };
+    if mounts == Mounts::Refuse && boundary.as_ref().is_some_and(|b| !b.is_clear()) {
+        return Err(Error::new(op, path, crossing()));
+    }
+
+    walk(parent.as_raw_fd(), name, mounts, boundary.as_ref()).ctx(op, path)
+}
+
+fn walk(
+    parent: RawFd,
+    name: CString,
+    mounts: Mounts,
+    boundary: Option<&Boundary>,
+) -> io::Result<RemoveOutcome> {
+    let Some(root) = descend(parent, &name)? else {
+        return Ok(RemoveOutcome::Removed);
+    };
+    let mut stack = vec![frame(&root, name)?];
+    let mut cur = root;
+
+    loop {
+        let top = stack.last_mut().expect("stack is never empty in the loop");
+        if let Some(child) = top.entries.pop() {
+            let st = match lstatat(cur.as_raw_fd(), &child) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                r => r?,
+            };
+
+            if !is_dir(&st) {
+                match unlinkat(cur.as_raw_fd(), &child, 0) {
+                    // NOTE: Swapped for a directory since the stat, so look again
+                    Err(e) if e.kind() == io::ErrorKind::IsADirectory => top.entries.push(child),
+                    r => r?,
+                }
+                continue;
+            }
+
+            #[allow(clippy::unnecessary_cast)]
+            if boundary.is_some_and(|b| b.excludes(st.st_dev as u64, st.st_ino as u64)) {
+                if mounts == Mounts::Refuse {
+                    return Err(crossing());
+                }
+                tracing::debug!("Skipping mount point {child:?}");
+                top.skipped = true;
+                continue;
+            }
+
+            if let Some(fd) = descend(cur.as_raw_fd(), &child)? {
+                stack.push(frame(&fd, child)?);
+                cur = fd;
+            }
+            continue;
+        }
+
+        let done = stack.pop().expect("stack is never empty in the loop");
+        let Some(up) = stack.last_mut() else {
+            drop(cur);
+            if done.skipped {
+                return Ok(RemoveOutcome::SkippedNotEmpty);
+            }
+            return unlinkat(parent, &done.name, libc::AT_REMOVEDIR)
+                .map(|()| RemoveOutcome::Removed);
+        };
+
+        let fd = open(cur.as_raw_fd(), c"..", libc::O_NOFOLLOW)?;
+        let st = fstat(fd.as_raw_fd())?;
+        if (st.st_dev, st.st_ino) != (up.dev, up.ino) {
+            return Err(io::Error::other("directory was moved during removal"));
+        }
+        cur = fd;
+
+        if done.skipped {
+            up.skipped = true;
+        } else {
+            unlinkat(cur.as_raw_fd(), &done.name, libc::AT_REMOVEDIR)?;
+        }
+    }
+}
+
+/// Opens the directory `name` under `dirfd` without following symlinks
+///
+/// If `name` is no longer a directory, it is unlinked instead and `None` is returned.
+fn descend(dirfd: RawFd, name: &CStr) -> io::Result<Option<OwnedFd>> {
+    match open(dirfd, name, libc::O_NOFOLLOW) {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ELOOP | libc::ENOTDIR)) => {
+            tracing::debug!("{name:?} stopped being a directory during removal");
+            unlinkat(dirfd, name, 0).map(|()| None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn frame(fd: &OwnedFd, name: CString) -> io::Result<Frame> {
+    let st = fstat(fd.as_raw_fd())?;
+    Ok(Frame {
+        name,
+        dev: st.st_dev,
+        ino: st.st_ino,
+        entries: list(fd)?,
+        skipped: false,
+    })
+}
+
+/// Lists the names in a directory, excluding `.` and `..`
+fn list(fd: &OwnedFd) -> io::Result<Vec<CString>> {
+    let dup = fd.try_clone()?;
+    let dir = unsafe { libc::fdopendir(dup.as_raw_fd()) };
+    if dir.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // NOTE: The stream owns the descriptor now
+    mem::forget(dup);
+
+    let mut names = Vec::new();
+    let result = loop {
+        unsafe { *libc::__errno_location() = 0 };
+        let ent = unsafe { libc::readdir(dir) };
+        if ent.is_null() {
+            let e = io::Error::last_os_error();
+            break if e.raw_os_error() == Some(0) {
+                Ok(names)
+            } else {
+                Err(e)
+            };
+        }
+
+        let name = unsafe { CStr::from_ptr((*ent).d_name.as_ptr()) };
+        if name != c"." && name != c".." {
+            names.push(name.to_owned());
+        }
+    };
+
+    unsafe { libc::closedir(dir) };
+    result
+}
+
+fn cstr(s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn is_dir(st: &libc::stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn open(dirfd: RawFd, name: &CStr, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let flags = flags | libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let fd = cvt(unsafe { libc::openat(dirfd, name.as_ptr(), flags) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn lstatat(dirfd: RawFd, name: &CStr) -> io::Result<libc::stat> {
+    let mut st = unsafe { mem::zeroed() };
+    cvt(unsafe { libc::fstatat(dirfd, name.as_ptr(), &mut st, libc::AT_SYMLINK_NOFOLLOW) })?;
+    Ok(st)
+}
+
+fn fstat(fd: RawFd) -> io::Result<libc::stat> {
+    let mut st = unsafe { mem::zeroed() };
+    cvt(unsafe { libc::fstat(fd, &mut st) })?;
+    Ok(st)
+}
+
+fn unlinkat(dirfd: RawFd, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+    match cvt(unsafe { libc::unlinkat(dirfd, name.as_ptr(), flags) }) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r.map(drop),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::*;
+    use crate::{mkf_p, tempdir};
+
+    #[test]
+    fn descend_never_follows_swapped_symlinks() {
+        let t = tempdir().unwrap();
+        let root = &t.path().join("swap");
+        mkf_p(root.join("outside/keep")).unwrap();
+        fs::create_dir(root.join("tree")).unwrap();
+        symlink("../outside", root.join("tree/sub")).unwrap();
+
+        // NOTE: Pretend `sub` was a directory when it was stat'd and got swapped afterwards
+        let tree = open(
+            libc::AT_FDCWD,
+            &cstr(root.join("tree").as_os_str()).unwrap(),
+            0,
+        )
+        .unwrap();
+        assert!(descend(tree.as_raw_fd(), c"sub").unwrap().is_none());
+        assert!(root.join("outside/keep").exists() && !root.join("tree/sub").exists());
+    }
+
+    #[test]
+    fn remove_tree_deeper_than_path_max() {
+        let t = tempdir().unwrap();
+        let root = &t.path().join("deep");
+        fs::create_dir_all(root).unwrap();
+
+        let mut fd = open(libc::AT_FDCWD, &cstr(root.as_os_str()).unwrap(), 0).unwrap();
+        for _ in 0..(libc::PATH_MAX / 2 + 16) {
+            cvt(unsafe { libc::mkdirat(fd.as_raw_fd(), c"d".as_ptr(), 0o755) }).unwrap();
+            fd = open(fd.as_raw_fd(), c"d", 0).unwrap();
+        }
+        drop(fd);
+
+        assert!(remove_tree(Op::RmdirR, root, Mounts::Cross).is_ok() && !root.exists());
+    }
+}
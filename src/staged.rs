@@ -0,0 +1,232 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions, Permissions, rename, symlink_metadata},
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{Action, Context, Error, Op, Result, atomic::sync_parent, dry, rmf, tmp_sibling};
+
+/// # A file being written that only appears once complete.
+/// Created by `mkf_staged()` and `MkfOptions::stage()`. Where supported, the file is unnamed until
+/// `commit()` links it into place; otherwise it's a hidden temporary sibling that gets renamed. If
+/// dropped without committing, nothing is left behind.
+///
+/// During a dry run, writes are discarded and committing only records the write.
+#[derive(Debug)]
+#[must_use = "the file is discarded unless committed"]
+pub struct StagedFile {
+    file: File,
+    path: PathBuf,
+    named: Option<Named>,
+    dry: bool,
+}
+
+/// A hidden temporary sibling, removed when dropped unless it was renamed into place
+#[derive(Debug)]
+struct Named(Option<PathBuf>);
+
+impl Drop for Named {
+    fn drop(&mut self) {
+        if let Some(tmp) = self.0.take()
+            && let Err(e) = dry::bypass(|| rmf(&tmp))
+        {
+            tracing::warn!("Failed to remove staged file {tmp:?}: {e}");
+        }
+    }
+}
+
+impl StagedFile {
+    /// # The open file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// # Whether the file is unnamed, rather than a temporary sibling.
+    pub fn is_anonymous(&self) -> bool {
+        self.named.is_none()
+    }
+
+    /// # Moves the complete file into place.
+    /// Syncs the file, then atomically replaces anything at the path. Returns the open file.
+    pub fn commit(self) -> Result<File> {
+        let Self {
+            file,
+            path,
+            mut named,
+            dry,
+        } = self;
+        if dry {
+            dry::record(Action::Write(path));
+            return Ok(file);
+        }
+        file.sync_all().ctx(Op::MkfStaged, &path)?;
+
+        let tmp = match named.as_mut().and_then(|n| n.0.take()) {
+            Some(tmp) => tmp,
+            None => {
+                tmp_sibling(&path, |tmp| link(&file, tmp))
+                    .ctx(Op::MkfStaged, &path)?
+                    .0
+            }
+        };
+        rename(&tmp, &path)
+            .inspect_err(|_| {
+                let _ = rmf(&tmp);
+            })
+            .and_then(|()| sync_parent(&path))
+            .ctx(Op::MkfStaged, &path)?;
+        Ok(file)
+    }
+}
+
+impl Write for StagedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Opens a staged file for `path`, whose parent must exist
+pub(crate) fn open(path: &Path, mode: Option<u32>) -> Result<StagedFile> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if dry::active() {
+        if symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+            return Err(Error::new(
+                Op::MkfStaged,
+                path,
+                io::ErrorKind::IsADirectory.into(),
+            ));
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .ctx(Op::MkfStaged, path)?;
+        return Ok(StagedFile {
+            file,
+            path: path.to_path_buf(),
+            named: None,
+            dry: true,
+        });
+    }
+
+    let (file, named) = match anonymous(dir) {
+        Ok(f) => (f, None),
+        // NOTE: Kernels or filesystems without O_TMPFILE reject it in a few different ways
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::EOPNOTSUPP | libc::EISDIR | libc::EINVAL)
+            ) =>
+        {
+            tracing::debug!("O_TMPFILE is unsupported in {dir:?}, using a named file");
+            let (tmp, f) = tmp_sibling(path, |tmp| {
+                OpenOptions::new().write(true).create_new(true).open(tmp)
+            })
+            .ctx(Op::MkfStaged, path)?;
+            (f, Some(Named(Some(tmp))))
+        }
+        Err(e) => return Err(Error::new(Op::MkfStaged, path, e)),
+    };
+
+    if let Some(mode) = mode {
+        file.set_permissions(Permissions::from_mode(mode))
+            .ctx(Op::MkfStaged, path)?;
+    }
+    Ok(StagedFile {
+        file,
+        path: path.to_path_buf(),
+        named,
+        dry: false,
+    })
+}
+
+fn anonymous(dir: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .mode(0o666)
+        .open(dir)
+}
+
+/// Gives an unnamed file a name
+fn link(file: &File, path: &Path) -> io::Result<()> {
+    let to = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // NOTE: Linking through /proc doesn't need CAP_DAC_READ_SEARCH, unlike AT_EMPTY_PATH
+    let proc = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .expect("formatted path has no NUL");
+    let ret = unsafe {
+        libc::linkat(
+            libc::AT_FDCWD,
+            proc.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let e = io::Error::last_os_error();
+    if e.kind() != io::ErrorKind::NotFound {
+        return Err(e);
+    }
+    let ret = unsafe {
+        libc::linkat(
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::AT_EMPTY_PATH,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{read, read_dir};
+
+    use super::*;
+    use crate::{MkfOptions, mkf_staged, tempdir};
+
+    #[test]
+    fn invisible_until_committed() {
+        let t = tempdir().unwrap();
+        let f = t.path().join("a/artifact");
+
+        let mut staged = MkfOptions::new()
+            .parents(true)
+            .mode(0o640)
+            .stage(&f)
+            .unwrap();
+        staged.write_all(b"big").unwrap();
+        let visible = read_dir(t.path().join("a")).unwrap().count();
+        assert_eq!(visible, usize::from(!staged.is_anonymous()));
+        staged.commit().unwrap();
+        assert_eq!(read(&f).unwrap(), b"big");
+        assert_eq!(f.metadata().unwrap().permissions().mode() & 0o777, 0o640);
+
+        drop(mkf_staged(&f).unwrap());
+        assert_eq!(read(&f).unwrap(), b"big");
+        assert_eq!(read_dir(t.path().join("a")).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    env,
+    fs::{DirBuilder, File, OpenOptions, rename},
+    hash::{BuildHasher, RandomState},
+    io,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{Action, Context, Error, Op, Result, dry, rmr};
+
+/// How many names to try before giving up
+pub(crate) const ATTEMPTS: usize = 64;
+
+/// Removes a path recursively when dropped, unless disarmed
+#[derive(Debug)]
+struct Remover {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl Remover {
+    /// Moves the path to `dst` and disarms, or pretends to during a dry run
+    fn persist(&mut self, dst: &Path) -> Result<()> {
+        if dry::active() {
+            dry::record(Action::Move(self.path.clone(), dst.to_path_buf()));
+            return Ok(());
+        }
+        rename(&self.path, dst).ctx2(Op::Mv, &self.path, dst)?;
+        self.armed = false;
+        Ok(())
+    }
+}
+
+impl Drop for Remover {
+    fn drop(&mut self) {
+        if self.armed
+            && let Err(e) = dry::bypass(|| rmr(&self.path))
+        {
+            tracing::warn!("Failed to remove temporary {:?}: {e}", self.path);
+        }
+    }
+}
+
+/// # A temporary directory, removed recursively when dropped.
+/// Created by `tempdir()` and `tempdir_in()`.
+#[derive(Debug)]
+#[must_use = "the directory is removed when this is dropped"]
+pub struct TempDir(Remover);
+
+impl TempDir {
+    /// # The path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.0.path
+    }
+
+    /// # Keeps the directory, moving it to `path`.
+    /// `path` must be on the same filesystem. The directory keeps its `0700` mode.
+    pub fn persist<P>(mut self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.0.persist(path.as_ref())
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+/// # A temporary file, removed when dropped.
+/// Created by `tempfile()` and `tempfile_in()`.
+#[derive(Debug)]
+#[must_use = "the file is removed when this is dropped"]
+pub struct TempFile {
+    remover: Remover,
+    file: File,
+}
+
+impl TempFile {
+    /// # The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.remover.path
+    }
+
+    /// # The open file, for reading and writing.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// # Keeps the file, moving it to `path`.
+    /// `path` must be on the same filesystem. The file keeps its `0600` mode. Returns the open file.
+    pub fn persist<P>(self, path: P) -> Result<File>
+    where
+        P: AsRef<Path>,
+    {
+        let Self { mut remover, file } = self;
+        remover.persist(path.as_ref()).map(|()| file)
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+/// # Creates a temporary directory in `$TMPDIR`.
+/// See `tempdir_in()`.
+pub fn tempdir() -> Result<TempDir> {
+    tempdir_in(env::temp_dir())
+}
+
+/// # Creates a temporary directory in `parent`.
+/// The directory gets a unique name and mode `0700`, and never reuses an existing entry.
+pub fn tempdir_in<P>(parent: P) -> Result<TempDir>
+where
+    P: AsRef<Path>,
+{
+    let parent = parent.as_ref();
+    let path = unique(Op::TempDir, parent, |p| {
+        DirBuilder::new().mode(0o700).create(p)
+    })?;
+    Ok(TempDir(Remover { path, armed: true }))
+}
+
+/// # Creates a temporary file in `$TMPDIR`.
+/// See `tempfile_in()`.
+pub fn tempfile() -> Result<TempFile> {
+    tempfile_in(env::temp_dir())
+}
+
+/// # Creates a temporary file in `parent`.
+/// The file gets a unique name and mode `0600`, and is opened with `O_EXCL` so it never reuses an
+/// existing entry.
+pub fn tempfile_in<P>(parent: P) -> Result<TempFile>
+where
+    P: AsRef<Path>,
+{
+    let parent = parent.as_ref();
+    let mut file = None;
+    let path = unique(Op::TempFile, parent, |p| {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(p)
+            .map(|f| file = Some(f))
+    })?;
+    let file = file.expect("file is opened when a name is found");
+    Ok(TempFile {
+        remover: Remover { path, armed: true },
+        file,
+    })
+}
+
+/// Calls `create` on random names under `parent` until one doesn't already exist
+fn unique<F>(op: Op, parent: &Path, mut create: F) -> Result<PathBuf>
+where
+    F: FnMut(&Path) -> io::Result<()>,
+{
+    for _ in 0..ATTEMPTS {
+        let path = parent.join(format!(".fshelpers.{}.{:016x}", process::id(), random()));
+        match create(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            r => return r.ctx(op, parent).map(|()| path),
+        }
+    }
+    Err(Error::new(
+        op,
+        parent,
+        io::Error::new(io::ErrorKind::AlreadyExists, "ran out of unique names"),
+    ))
+}
+
+/// A random number for making names unique
+pub(crate) fn random() -> u64 {
+    RandomState::new().hash_one(process::id())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn temps_are_private_and_removed() {
+        let mode = |p: &Path| p.metadata().unwrap().permissions().mode() & 0o7777;
+        let d = tempdir().unwrap();
+        let f = tempfile_in(&d).unwrap();
+        assert_eq!((mode(d.path()), mode(f.path())), (0o700, 0o600));
+        assert_ne!(tempfile_in(&d).unwrap().path(), f.path());
+
+        let kept = d.path().join("kept");
+        let mut file = f.persist(&kept).unwrap();
+        file.write_all(b"hi").unwrap();
+        assert_eq!(std::fs::read(&kept).unwrap(), b"hi");
+
+        let path = d.path().to_path_buf();
+        drop(d);
+        assert!(!path.exists());
+    }
+}
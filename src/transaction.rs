@@ -0,0 +1,333 @@
+use std::{
+    fs::{read_dir, rename, symlink_metadata},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Context, CreateOutcome, Error, FileKind, MkdirOptions, MkfOptions, Op, RemoveOutcome, Result,
+    atomic::rename_noreplace, dry, guard, journal::Journal, kind, missing_parents, rm, rmdir, rmf,
+    rmr, tmp_sibling, vacant,
+};
+
+/// A change made by a transaction
+#[derive(Debug)]
+enum Entry {
+    Created(PathBuf),
+    /// A removed path, moved aside to `stash`
+    Removed {
+        path: PathBuf,
+        stash: PathBuf,
+    },
+}
+
+/// # A group of changes that can be undone.
+/// Journals every path its helpers create, and moves removed paths aside to a hidden sibling
+/// instead of deleting them. `rollback()` undoes the changes in reverse order; `commit()` keeps
+/// them and deletes what was moved aside. Dropping an uncommitted transaction rolls it back.
+///
+/// A journaled transaction also writes ahead to a journal file, so `recover()` can deal with it
+/// after a crash.
+///
+/// During a `DryRun`, its helpers only pretend like the plain ones do, and nothing is journaled or
+/// moved aside, so there is nothing to commit or roll back.
+#[derive(Debug, Default)]
+#[must_use = "the transaction is rolled back when dropped"]
+pub struct Transaction {
+    journal: Vec<Entry>,
+    wal: Option<Journal>,
+}
+
+impl Transaction {
+    /// # Starts an empty transaction.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # Starts an empty transaction with an on-disk journal.
+    /// The journal is written to `state_dir`, which is created if needed, and deleted once the
+    /// transaction is committed or rolled back. Pass the same directory to `recover()` after a
+    /// crash. Each journal has a unique name and stays locked while the transaction is alive, so
+    /// transactions and `recover()` can share the directory.
+    pub fn journaled<P>(state_dir: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        if dry::active() {
+            return Ok(Self::new());
+        }
+        Ok(Self {
+            journal: Vec::new(),
+            wal: Some(Journal::create(state_dir.as_ref())?),
+        })
+    }
+
+    /// # Creates a directory as part of this transaction.
+    /// See `mkdir()`.
+    pub fn mkdir<P>(&mut self, dir: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        self.intend(dir)?;
+        let outcome = MkdirOptions::new().mkdir(dir)?;
+        self.created(dir, outcome);
+        Ok(outcome)
+    }
+
+    /// # Creates a directory and all its parents as part of this transaction.
+    /// See `mkdir_p()`. Parents that get created are journaled too.
+    pub fn mkdir_p<P>(&mut self, dir: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        self.mkparent(dir)?;
+        self.intend(dir)?;
+        let outcome = MkdirOptions::new().parents(true).mkdir(dir)?;
+        self.created(dir, outcome);
+        Ok(outcome)
+    }
+
+    /// # Creates a file as part of this transaction.
+    /// See `mkf()`.
+    pub fn mkf<P>(&mut self, file: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        self.intend(file)?;
+        let outcome = MkfOptions::new().mkf(file)?;
+        self.created(file, outcome);
+        Ok(outcome)
+    }
+
+    /// # Creates a file, with parents, as part of this transaction.
+    /// See `mkf_p()`. Parents that get created are journaled too.
+    pub fn mkf_p<P>(&mut self, file: P) -> Result<CreateOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        self.mkparent(file)?;
+        self.intend(file)?;
+        let outcome = MkfOptions::new().parents(true).mkf(file)?;
+        self.created(file, outcome);
+        Ok(outcome)
+    }
+
+    /// # Removes a file or symlink as part of this transaction.
+    /// See `rmf()`.
+    pub fn rmf<P>(&mut self, file: P) -> Result<RemoveOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let file = file.as_ref();
+        if dry::active() {
+            return rmf(file);
+        }
+        match kind(file).map_err(|e| Error::new(Op::Rmf, file, e.into_io()))? {
+            None => Ok(RemoveOutcome::WasMissing),
+            Some(FileKind::Dir) => Err(Error::new(
+                Op::Rmf,
+                file,
+                io::ErrorKind::IsADirectory.into(),
+            )),
+            Some(_) => self.stash(Op::Rmf, file),
+        }
+    }
+
+    /// # Removes a directory as part of this transaction.
+    /// See `rmdir()`.
+    pub fn rmdir<P>(&mut self, dir: P) -> Result<RemoveOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        if dry::active() {
+            return rmdir(dir);
+        }
+        match kind(dir).map_err(|e| Error::new(Op::Rmdir, dir, e.into_io()))? {
+            None => Ok(RemoveOutcome::WasMissing),
+            Some(FileKind::Dir) => {
+                if read_dir(dir).ctx(Op::Rmdir, dir)?.next().is_some() {
+                    return Ok(RemoveOutcome::SkippedNotEmpty);
+                }
+                self.stash(Op::Rmdir, dir)
+            }
+            Some(_) => Err(Error::new(
+                Op::Rmdir,
+                dir,
+                io::ErrorKind::NotADirectory.into(),
+            )),
+        }
+    }
+
+    /// # Removes a path, recursively if needed, as part of this transaction.
+    /// See `rmr()`. The whole path is moved aside at once.
+    pub fn rmr<P>(&mut self, path: P) -> Result<RemoveOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        guard::check(Op::Rmr, path)?;
+        if dry::active() {
+            return rmr(path);
+        }
+        match kind(path).map_err(|e| Error::new(Op::Rmr, path, e.into_io()))? {
+            None => Ok(RemoveOutcome::WasMissing),
+            Some(_) => self.stash(Op::Rmr, path),
+        }
+    }
+
+    /// # Keeps the changes.
+    /// Deletes everything that was moved aside.
+    pub fn commit(mut self) -> Result<()> {
+        dry::bypass(|| self.finish())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.commit()?;
+        }
+        let mut result = Ok(());
+        for entry in std::mem::take(&mut self.journal) {
+            if let Entry::Removed { stash, .. } = entry
+                && let Err(e) = rmr(&stash)
+            {
+                tracing::warn!("Failed to delete {stash:?} while committing: {e}");
+                result = result.and(Err(e));
+            }
+        }
+        // NOTE: On failure, the journal is left behind for `recover()` to try again
+        let wal = self.wal.take();
+        result?;
+        wal.map_or(Ok(()), Journal::finish)
+    }
+
+    /// # Undoes the changes.
+    /// Created paths are removed and removed paths are moved back, newest first. Created
+    /// directories that have since been populated are left alone.
+    pub fn rollback(mut self) -> Result<()> {
+        self.undo()
+    }
+
+    fn undo(&mut self) -> Result<()> {
+        dry::bypass(|| self.restore())
+    }
+
+    fn restore(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        while let Some(entry) = self.journal.pop() {
+            let r = match &entry {
+                Entry::Created(path) => rm(path).map(|outcome| {
+                    if outcome == RemoveOutcome::SkippedNotEmpty {
+                        tracing::warn!("Leaving {path:?} behind, since it is no longer empty");
+                    }
+                }),
+                Entry::Removed { path, stash } => rename(stash, path).ctx2(Op::Mv, stash, path),
+            };
+            if let Err(e) = r {
+                tracing::warn!("Failed to undo {entry:?}: {e}");
+                result = result.and(Err(e));
+            }
+        }
+        let wal = self.wal.take();
+        result?;
+        wal.map_or(Ok(()), Journal::finish)
+    }
+
+    /// Abandons the transaction like a crash would, closing the journal without undoing anything
+    #[cfg(test)]
+    pub(crate) fn crash(mut self) {
+        self.journal.clear();
+        self.wal.take();
+    }
+
+    /// Journals that `path` is about to be created, if it's missing
+    fn intend(&mut self, path: &Path) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) if !dry::active() && symlink_metadata(path).is_err() => {
+                wal.create_intent(path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Creates the missing parents of `path`, journaling those that were created
+    fn mkparent(&mut self, path: &Path) -> Result<()> {
+        for dir in missing_parents(path) {
+            self.intend(dir)?;
+        }
+        let mut created = Vec::new();
+        let result = MkdirOptions::new().mkparent_into(path, &mut created);
+        if !dry::active() {
+            self.journal.extend(created.into_iter().map(Entry::Created));
+        }
+        result
+    }
+
+    fn created(&mut self, path: &Path, outcome: CreateOutcome) {
+        if outcome.created() && !dry::active() {
+            self.journal.push(Entry::Created(path.to_path_buf()));
+        }
+    }
+
+    /// Moves `path` aside so it can be restored
+    fn stash(&mut self, op: Op, path: &Path) -> Result<RemoveOutcome> {
+        // NOTE: The name is picked before journaling, so a stale stash is never mistaken for this one
+        let (stash, ()) = tmp_sibling(path, vacant).ctx(op, path)?;
+        if let Some(wal) = &mut self.wal {
+            wal.stash_intent(path, &stash)?;
+        }
+        rename_noreplace(path, &stash).ctx2(op, path, &stash)?;
+        tracing::debug!("Moved {path:?} aside to {stash:?}");
+        self.journal.push(Entry::Removed {
+            path: path.to_path_buf(),
+            stash,
+        });
+        Ok(RemoveOutcome::Removed)
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.journal.is_empty() || self.wal.is_some() {
+            tracing::debug!("Rolling back an uncommitted transaction");
+            let _ = self.undo();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{mkf_p, tempdir};
+
+    #[test]
+    fn rollback_on_drop_and_commit() {
+        let t = tempdir().unwrap();
+        let d = t.path();
+        mkf_p(d.join("old")).unwrap();
+
+        {
+            let mut tx = Transaction::new();
+            assert!(tx.mkf_p(d.join("a/b/new")).unwrap().created());
+            assert!(tx.rmf(d.join("old")).unwrap().removed());
+            assert!(tx.mkdir_p(d.join("c")).unwrap().created());
+            assert!(d.join("a/b/new").exists() && !d.join("old").exists());
+        }
+        assert!(!d.join("a").exists() && !d.join("c").exists());
+        assert!(d.join("old").exists());
+
+        let mut tx = Transaction::new();
+        tx.rmr(d.join("old")).unwrap();
+        tx.mkdir(d.join("c")).unwrap();
+        tx.commit().unwrap();
+        let names = read_dir(d)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["c"]);
+    }
+}